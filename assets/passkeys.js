@@ -0,0 +1,101 @@
+function base64urlToBuffer(value) {
+    const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+    const padded = base64 + "=".repeat((4 - (base64.length % 4)) % 4);
+    return Uint8Array.from(atob(padded), (c) => c.charCodeAt(0)).buffer;
+}
+
+function bufferToBase64url(buffer) {
+    const bytes = new Uint8Array(buffer);
+    let binary = "";
+    bytes.forEach((b) => (binary += String.fromCharCode(b)));
+    return btoa(binary)
+        .replace(/\+/g, "-")
+        .replace(/\//g, "_")
+        .replace(/=+$/, "");
+}
+
+async function registerPasskey() {
+    const response = document.getElementById("passkey-response");
+    try {
+        const options = await (
+            await fetch("./passkey/register/start", {
+                method: "POST",
+                headers: { "HX-Request": "true" },
+            })
+        ).json();
+        options.challenge = base64urlToBuffer(options.challenge);
+        options.user.id = base64urlToBuffer(options.user.id);
+        options.excludeCredentials = options.excludeCredentials.map((c) => ({
+            type: c.type,
+            id: base64urlToBuffer(c.id),
+        }));
+
+        const credential = await navigator.credentials.create({
+            publicKey: options,
+        });
+
+        const result = await fetch("./passkey/register/finish", {
+            method: "POST",
+            headers: {
+                "Content-Type": "application/json",
+                "HX-Request": "true",
+            },
+            body: JSON.stringify({
+                name: document.getElementById("passkey-name").value,
+                client_data_json: bufferToBase64url(
+                    credential.response.clientDataJSON,
+                ),
+                attestation_object: bufferToBase64url(
+                    credential.response.attestationObject,
+                ),
+            }),
+        });
+        response.textContent = await result.text();
+        htmx.trigger("#passkeys", "passkeysChanged");
+    } catch (e) {
+        response.textContent = "Adding your passkey was cancelled or failed";
+    }
+}
+
+async function loginWithPasskey() {
+    const response = document.getElementById("login-response");
+    try {
+        const options = await (
+            await fetch("./passkey/login/start", {
+                method: "POST",
+                headers: { "HX-Request": "true" },
+            })
+        ).json();
+        options.challenge = base64urlToBuffer(options.challenge);
+
+        const credential = await navigator.credentials.get({
+            publicKey: options,
+        });
+
+        const result = await fetch("./passkey/login/finish", {
+            method: "POST",
+            headers: {
+                "Content-Type": "application/json",
+                "HX-Request": "true",
+            },
+            body: JSON.stringify({
+                credential_id: credential.id,
+                client_data_json: bufferToBase64url(
+                    credential.response.clientDataJSON,
+                ),
+                authenticator_data: bufferToBase64url(
+                    credential.response.authenticatorData,
+                ),
+                signature: bufferToBase64url(credential.response.signature),
+            }),
+        });
+        if (result.ok) {
+            // Users with two-factor authentication still have to enter a code
+            window.location = result.headers.get("HX-Location") || "./home.html";
+        } else {
+            response.textContent = await result.text();
+        }
+    } catch (e) {
+        response.textContent = "Logging in with a passkey was cancelled or failed";
+    }
+}
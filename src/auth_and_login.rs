@@ -1,12 +1,21 @@
-use crate::{utilities, AppState};
+use crate::{
+    config::{PasswordPolicy, SessionConfig},
+    totp, utilities, AppState,
+};
 use axum::{
-    extract::State,
-    http::{Request, StatusCode},
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, StatusCode},
+    middleware::Next,
+    response::{Html, Response},
 };
+use axum_extra::extract::CookieJar;
+use hmac::{Hmac, Mac};
 use http::HeaderMap;
 use rand::Rng;
 use rand_chacha::rand_core::SeedableRng;
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use sqlx::{Row, SqlitePool};
 
 use argon2::{
@@ -24,6 +33,65 @@ pub struct LoginRequest {
 pub struct User {
     pub id: i32,
     pub username: String,
+    pub role: String,
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == "admin"
+    }
+}
+
+// What a personal API token is allowed to do
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ApiScope {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl ApiScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::ReadOnly => "read",
+            ApiScope::ReadWrite => "write",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<ApiScope> {
+        match scope {
+            "read" => Some(ApiScope::ReadOnly),
+            "write" => Some(ApiScope::ReadWrite),
+            _ => None,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match self {
+            ApiScope::ReadOnly => "Read only",
+            ApiScope::ReadWrite => "Read and write",
+        }
+    }
+}
+
+// Handlers behind the auth middleware take the logged in user as an argument
+#[async_trait]
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, HeaderMap);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        // Only missing if a route using this isn't behind the auth middleware
+        match parts.extensions.get::<User>() {
+            Some(user) => Ok(user.clone()),
+            None => {
+                let mut redirect_header: HeaderMap = HeaderMap::new();
+                redirect_header.insert("HX-Redirect", "./index.html".parse().unwrap());
+                Err((StatusCode::UNAUTHORIZED, redirect_header))
+            }
+        }
+    }
 }
 
 // Verify the username and password match stored credentials and return the user if so
@@ -46,75 +114,492 @@ pub async fn verify_login(username: &str, password: &str, pool: SqlitePool) -> O
     let hashed_password = query.try_get("hashed_password").unwrap();
     let user_id: i32 = query.try_get("id").unwrap();
 
+    if password_matches(password, hashed_password) {
+        get_user(user_id, pool.clone()).await
+    } else {
+        None
+    }
+}
+
+// Verify a password against the stored hash for an existing user
+pub async fn verify_password(user_id: i32, password: &str, pool: SqlitePool) -> bool {
+    let query = sqlx::query("SELECT hashed_password FROM users WHERE id=?")
+        .bind(user_id)
+        .fetch_optional(&pool)
+        .await
+        .unwrap_or_default();
+
+    match query {
+        Some(row) => password_matches(password, row.try_get("hashed_password").unwrap()),
+        None => false,
+    }
+}
+
+// Return true if the password matches the Argon2 hash and false otherwise
+fn password_matches(password: &str, hashed_password: &str) -> bool {
     // Parse hash
-    let parsed_hash = PasswordHash::new(hashed_password).unwrap();
+    let parsed_hash = match PasswordHash::new(hashed_password) {
+        Ok(hash) => hash,
+        Err(_e) => return false,
+    };
 
-    // Return true if hash matches and false otherwise
-    if Argon2::default()
+    Argon2::default()
         .verify_password(password.as_bytes(), &parsed_hash)
         .is_ok()
-    {
-        get_user(user_id, pool.clone()).await
+}
+
+// Confirm the cookie is valid and return the user if so, along with whether the session was extended
+pub async fn validate_cookie(
+    cookie_value: String,
+    token_secret: &str,
+    session_config: &SessionConfig,
+    pool: SqlitePool,
+) -> Option<(User, bool)> {
+    let current_time: i64 = utilities::get_epoch_time();
+
+    let query = sqlx::query(
+        "SELECT * FROM auth_tokens WHERE token_hash =? AND expiry > ? and revoked=false",
+    )
+    .bind(hash_token(&cookie_value, token_secret))
+    .bind(current_time)
+    .fetch_optional(&pool)
+    .await
+    .unwrap_or_default();
+
+    if let Some(query) = query {
+        let user_id: i32 = query
+            .try_get("user_id")
+            .expect("Unable to get user id from cookie");
+        let token_id: i32 = query.try_get("id").unwrap();
+        let expiry: i64 = query.try_get("expiry").unwrap();
+
+        record_use(&pool, "auth_tokens", "last_seen", token_id, current_time).await;
+
+        // Slide the expiry forward for sessions that are still in use close to the end
+        let extended = expiry - current_time < session_config.refresh_threshold_seconds;
+        if extended {
+            sqlx::query("UPDATE auth_tokens SET expiry=? WHERE id=?")
+                .bind(current_time + session_config.lifetime_seconds)
+                .bind(token_id)
+                .execute(&pool)
+                .await
+                .expect("Failed to extend session");
+        }
+
+        get_user(user_id, pool.clone())
+            .await
+            .map(|user| (user, extended))
     } else {
         None
     }
 }
 
-// Confirm the cookie is valid and return the user if so
-pub async fn validate_cookie(cookie_value: String, pool: SqlitePool) -> Option<User> {
+// Confirm a personal API token is valid and return the user and what the token may do if so
+pub async fn validate_api_token(
+    token: &str,
+    token_secret: &str,
+    pool: SqlitePool,
+) -> Option<(User, ApiScope)> {
     let current_time: i64 = utilities::get_epoch_time();
 
     let query =
-        sqlx::query("SELECT * FROM auth_tokens WHERE token =? AND expiry > ? and revoked=false")
-            .bind(cookie_value)
-            .bind(current_time)
+        sqlx::query("SELECT id,user_id,scope FROM api_tokens WHERE token_hash=? AND revoked=false")
+            .bind(hash_token(token, token_secret))
             .fetch_optional(&pool)
             .await
-            .unwrap_or_default();
+            .unwrap_or_default()?;
+    let token_id: i32 = query.try_get("id").unwrap();
+    let user_id: i32 = query.try_get("user_id").unwrap();
+    let scope = ApiScope::parse(query.try_get("scope").unwrap())?;
 
-    if let Some(query) = query {
-        let user_id: i32 = query
-            .try_get("user_id")
-            .expect("Unable to get user id from cookie");
-        get_user(user_id, pool.clone()).await
+    record_use(&pool, "api_tokens", "last_used", token_id, current_time).await;
+
+    get_user(user_id, pool.clone())
+        .await
+        .map(|user| (user, scope))
+}
+
+// Store when a session or API token was last used. This only writes when the stored time is a minute
+// or more out of date, so a page making several requests doesn't write for each one.
+async fn record_use(pool: &SqlitePool, table: &str, column: &str, id: i32, current_time: i64) {
+    sqlx::query(&format!(
+        "UPDATE {0} SET {1}=? WHERE id=? AND ({1} IS NULL OR {1} < ?)",
+        table, column
+    ))
+    .bind(current_time)
+    .bind(id)
+    .bind(current_time - 60)
+    .execute(pool)
+    .await
+    .expect("Failed to record when a token was used");
+}
+
+// Create a personal API token, returning the token which is only shown to the user once
+pub async fn create_api_token(
+    user_id: i32,
+    name: &str,
+    scope: ApiScope,
+    token_secret: &str,
+    pool: SqlitePool,
+) -> String {
+    let token = format!("cl_{}", generate_token());
+
+    sqlx::query(
+        "INSERT INTO api_tokens (user_id,name,token_hash,scope,created,revoked) values(?,?,?,?,?,?)",
+    )
+    .bind(user_id)
+    .bind(name)
+    .bind(hash_token(&token, token_secret))
+    .bind(scope.as_str())
+    .bind(utilities::get_epoch_time())
+    .bind(false)
+    .execute(&pool)
+    .await
+    .expect("Failed to create API token");
+
+    token
+}
+
+// Get a bearer token from the Authorization header if there is one
+fn get_bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("Authorization")?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim().to_string())
     } else {
         None
     }
 }
 
-// Middleware to check the auth_token cookie
-pub async fn auth<B>(
+// Middleware to check the auth_token cookie, or a personal API token sent as a bearer token
+pub async fn auth(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    mut request: Request<B>,
-) -> Result<Request<B>, (StatusCode, HeaderMap)> {
+    jar: CookieJar,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, HeaderMap)> {
+    if let Some(token) = get_bearer_token(request.headers()) {
+        if !state.app_config.features.api_tokens {
+            return Err((StatusCode::UNAUTHORIZED, HeaderMap::new()));
+        }
+        let Some((user, scope)) = validate_api_token(
+            &token,
+            &state.app_config.token_secret,
+            state.connection_pool.clone(),
+        )
+        .await
+        else {
+            return Err((StatusCode::UNAUTHORIZED, HeaderMap::new()));
+        };
+        if scope == ApiScope::ReadOnly && !request.method().is_safe() {
+            return Err((StatusCode::FORBIDDEN, HeaderMap::new()));
+        }
+        request.extensions_mut().insert(user);
+        request.extensions_mut().insert(scope);
+        return Ok(next.run(request).await);
+    }
+
     let mut redirect_header: HeaderMap = HeaderMap::new();
     redirect_header.insert("HX-Redirect", "./index.html".parse().unwrap());
-    let cookies_header = match headers.get("Cookie") {
-        Some(cookie) => cookie.to_str().unwrap().to_string(),
-        None => "".to_string(),
-    };
-    let cookies: Vec<&str> = cookies_header.split(';').collect();
-    let mut auth_cookie = "";
-    for cookie in cookies.iter() {
-        let kv: Vec<&str> = cookie.split('=').collect();
-        if kv[0] == "auth_token" {
-            auth_cookie = kv[1];
-        }
+    let auth_cookie = get_auth_token(&jar);
+    if auth_cookie.is_empty() {
+        return Err((StatusCode::UNAUTHORIZED, redirect_header));
     }
-    match validate_cookie(auth_cookie.to_string(), state.connection_pool.clone()).await {
-        Some(user) => {
-            request
-                .headers_mut()
-                .insert("username", user.username.parse().unwrap());
-            request.headers_mut().insert("user-id", user.id.into());
+    match validate_cookie(
+        auth_cookie.clone(),
+        &state.app_config.token_secret,
+        &state.app_config.session,
+        state.connection_pool.clone(),
+    )
+    .await
+    {
+        Some((user, extended)) => {
+            request.extensions_mut().insert(user);
 
-            Ok(request)
+            let mut response = next.run(request).await;
+            // Don't override a cookie set by the handler, such as on logout
+            if extended && !response.headers().contains_key("Set-Cookie") {
+                response.headers_mut().append(
+                    "Set-Cookie",
+                    session_cookie(
+                        &auth_cookie,
+                        state.app_config.session.lifetime_seconds,
+                        &state.app_config.session,
+                    )
+                    .parse()
+                    .unwrap(),
+                );
+            }
+            Ok(response)
         }
         None => Err((StatusCode::UNAUTHORIZED, redirect_header)),
     }
 }
 
+// Middleware to stop other sites triggering state changing requests with the user's cookie.
+// Requests must come from our own origin and carry the HX-Request header, which a cross site form can't set.
+pub async fn csrf_protection(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, Html<String>)> {
+    // API tokens aren't sent automatically by browsers so can't be used for CSRF
+    if request.method().is_safe() || get_bearer_token(request.headers()).is_some() {
+        return Ok(next.run(request).await);
+    }
+
+    let headers = request.headers();
+    let expected_origin = state.app_config.base_url.trim_end_matches('/');
+    // Browsers send Origin with state changing requests, Referer is a fallback for older ones
+    let origin = match headers.get("Origin") {
+        Some(origin) => origin.to_str().unwrap_or_default().to_string(),
+        None => headers
+            .get("Referer")
+            .and_then(|referer| referer.to_str().ok())
+            .map(|referer| referer_origin(referer).to_string())
+            .unwrap_or_default(),
+    };
+    let htmx_request = headers
+        .get("HX-Request")
+        .is_some_and(|value| value == "true");
+
+    if origin != expected_origin || !htmx_request {
+        println!(
+            "Rejected possible CSRF request: {} {} from origin '{}', HX-Request {}",
+            request.method(),
+            request.uri().path(),
+            origin,
+            htmx_request
+        );
+        return Err((
+            StatusCode::FORBIDDEN,
+            Html(
+                "This request didn't come from this site, please reload the page and try again"
+                    .to_string(),
+            ),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+// The scheme, host and port of a Referer URL
+fn referer_origin(referer: &str) -> &str {
+    let after_scheme = referer.find("://").map(|index| index + 3).unwrap_or(0);
+    match referer[after_scheme..].find('/') {
+        Some(index) => &referer[..after_scheme + index],
+        None => referer,
+    }
+}
+
+// Middleware for the admin console, which must sit behind the auth middleware
+pub async fn require_admin(request: Request, next: Next) -> Result<Response, StatusCode> {
+    match request.extensions().get::<User>() {
+        Some(user) if user.is_admin() && request.extensions().get::<ApiScope>().is_none() => {
+            Ok(next.run(request).await)
+        }
+        _ => Err(StatusCode::FORBIDDEN),
+    }
+}
+
+// Middleware for account settings, which can only be changed from a logged in browser and not with an API token
+pub async fn require_session(request: Request, next: Next) -> Result<Response, StatusCode> {
+    if request.extensions().get::<ApiScope>().is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(request).await)
+}
+
+// Get the auth token from the request cookies, or an empty string if there isn't one
+pub fn get_auth_token(jar: &CookieJar) -> String {
+    jar.get("auth_token")
+        .map(|cookie| cookie.value().to_string())
+        .unwrap_or_default()
+}
+
+// Build the Set-Cookie value for the auth token, use a max age of 0 to remove it
+pub fn session_cookie(token: &str, max_age: i64, session_config: &SessionConfig) -> String {
+    build_cookie("auth_token", token, max_age, session_config)
+}
+
+// Build a Set-Cookie value with the configured cookie attributes
+pub fn build_cookie(
+    name: &str,
+    value: &str,
+    max_age: i64,
+    session_config: &SessionConfig,
+) -> String {
+    let mut cookie = format!(
+        "{}={}; Max-Age={}; Path={}; HttpOnly; SameSite={}",
+        name, value, max_age, session_config.cookie_path, session_config.cookie_same_site
+    );
+    if session_config.cookie_secure {
+        cookie.push_str("; Secure");
+    }
+    cookie
+}
+
+// Issue a new auth token for the user
+pub async fn create_session(
+    user_id: i32,
+    user_agent: &str,
+    ip_address: &str,
+    token_secret: &str,
+    session_config: &SessionConfig,
+    pool: SqlitePool,
+) -> String {
+    let token = generate_token();
+    let current_time: i64 = utilities::get_epoch_time();
+    let expiry: i64 = current_time + session_config.lifetime_seconds;
+
+    sqlx::query(
+        "INSERT INTO auth_tokens (token_hash,user_id,expiry,revoked,user_agent,ip_address,created,last_seen) values(?,?,?,?,?,?,?,?)",
+    )
+    .bind(hash_token(&token, token_secret))
+    .bind(user_id)
+    .bind(expiry)
+    .bind(false)
+    .bind(user_agent.chars().take(300).collect::<String>())
+    .bind(ip_address)
+    .bind(current_time)
+    .bind(current_time)
+    .execute(&pool)
+    .await
+    .expect("Failed to create access token");
+
+    token
+}
+
+pub async fn totp_enabled(user_id: i32, pool: SqlitePool) -> bool {
+    sqlx::query("SELECT id FROM users WHERE id=? AND totp_enabled=1")
+        .bind(user_id)
+        .fetch_optional(&pool)
+        .await
+        .unwrap_or_default()
+        .is_some()
+}
+
+// Check a TOTP code for the user, refusing codes from a time step that has already been used
+pub async fn verify_totp(user_id: i32, code: &str, pool: SqlitePool) -> bool {
+    let query = sqlx::query("SELECT totp_secret FROM users WHERE id=? AND totp_enabled=1")
+        .bind(user_id)
+        .fetch_optional(&pool)
+        .await
+        .unwrap_or_default();
+    let Some(row) = query else {
+        return false;
+    };
+    let secret: String = row.try_get("totp_secret").unwrap();
+
+    match totp::verify_code(&secret, code, utilities::get_epoch_time()) {
+        Some(step) => sqlx::query(
+            "UPDATE users SET totp_last_step=? WHERE id=? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+        )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(&pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+        .unwrap_or(false),
+        None => false,
+    }
+}
+
+// Generate a fresh set of recovery codes for the user, replacing any they already had
+pub async fn create_recovery_codes(
+    user_id: i32,
+    token_secret: &str,
+    pool: SqlitePool,
+) -> Vec<String> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id=?")
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .expect("Failed to remove recovery codes");
+
+    let mut codes = vec![];
+    for _ in 0..10 {
+        let token = generate_token();
+        let code = format!("{}-{}", &token[0..5], &token[5..10]);
+        sqlx::query("INSERT INTO recovery_codes (user_id,code_hash,used) values(?,?,?)")
+            .bind(user_id)
+            .bind(hash_token(&normalise_recovery_code(&code), token_secret))
+            .bind(false)
+            .execute(&pool)
+            .await
+            .expect("Failed to create recovery code");
+        codes.push(code);
+    }
+    codes
+}
+
+// Use up one of the user's recovery codes, returning false if it doesn't match an unused one
+pub async fn use_recovery_code(
+    user_id: i32,
+    code: &str,
+    token_secret: &str,
+    pool: SqlitePool,
+) -> bool {
+    sqlx::query(
+        "UPDATE recovery_codes SET used=true WHERE user_id=? AND code_hash=? AND used=false",
+    )
+    .bind(user_id)
+    .bind(hash_token(&normalise_recovery_code(code), token_secret))
+    .execute(&pool)
+    .await
+    .map(|result| result.rows_affected() > 0)
+    .unwrap_or(false)
+}
+
+fn normalise_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase()
+}
+
+// Remember a user who has entered their password but still needs to give a second factor
+pub async fn create_pending_login(user_id: i32, token_secret: &str, pool: SqlitePool) -> String {
+    let token = generate_token();
+    let pending_duration_in_seconds: i64 = 300;
+    let expiry: i64 = utilities::get_epoch_time() + pending_duration_in_seconds;
+
+    sqlx::query("INSERT INTO pending_logins (token_hash,user_id,expiry,used) values(?,?,?,?)")
+        .bind(hash_token(&token, token_secret))
+        .bind(user_id)
+        .bind(expiry)
+        .bind(false)
+        .execute(&pool)
+        .await
+        .expect("Failed to create pending login");
+
+    token
+}
+
+pub async fn get_pending_login(token: &str, token_secret: &str, pool: SqlitePool) -> Option<User> {
+    let query = sqlx::query(
+        "SELECT user_id FROM pending_logins WHERE token_hash=? AND expiry > ? AND used=false",
+    )
+    .bind(hash_token(token, token_secret))
+    .bind(utilities::get_epoch_time())
+    .fetch_optional(&pool)
+    .await
+    .unwrap_or_default()?;
+
+    get_user(query.try_get("user_id").unwrap(), pool.clone()).await
+}
+
+// Mark the pending login as finished, returning false if it had already been used
+pub async fn complete_pending_login(token: &str, token_secret: &str, pool: SqlitePool) -> bool {
+    sqlx::query("UPDATE pending_logins SET used=true WHERE token_hash=? AND used=false")
+        .bind(hash_token(token, token_secret))
+        .execute(&pool)
+        .await
+        .map(|result| result.rows_affected() == 1)
+        .unwrap_or(false)
+}
+
 pub fn generate_token() -> String {
     let char_set: Vec<&str> = vec![
         "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r",
@@ -129,6 +614,14 @@ pub fn generate_token() -> String {
     token
 }
 
+// Only a keyed hash of each auth token is stored, so a copy of the database can't be used to log in
+pub fn hash_token(token: &str, token_secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(token_secret.as_bytes())
+        .expect("HMAC can take a key of any size");
+    mac.update(token.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
 pub fn hash_password(password: String) -> String {
     let salt = SaltString::generate(rand_chacha::ChaCha20Rng::from_entropy());
     //let salt = SaltString::from_b64("bBYj4SR3zS3fSA+o5yGW6w").unwrap();
@@ -143,12 +636,83 @@ pub fn hash_password(password: String) -> String {
 }
 
 pub async fn get_user(user_id: i32, pool: SqlitePool) -> Option<User> {
-    match sqlx::query_as::<_, User>("SELECT id,username FROM users WHERE id=?")
+    sqlx::query_as::<_, User>("SELECT id,username,role FROM users WHERE id=? AND active=1")
         .bind(user_id)
         .fetch_one(&pool)
         .await
-    {
-        Ok(value) => Some(value),
-        Err(_e) => None,
+        .ok()
+}
+
+// Check a new password meets the password policy, returning a message for the user if not
+pub fn check_new_password(
+    password: &str,
+    confirm_password: &str,
+    policy: &PasswordPolicy,
+) -> Result<(), String> {
+    if password != confirm_password {
+        return Err("Your passwords must match".to_string());
+    }
+    let length = password.chars().count();
+    if length < policy.min_length {
+        return Err(format!(
+            "Your password must be at least {} characters long",
+            policy.min_length
+        ));
+    }
+    if length > policy.max_length {
+        return Err(format!(
+            "Your password must be no more than {} characters long",
+            policy.max_length
+        ));
+    }
+    if policy.require_letter && !password.chars().any(|c| c.is_alphabetic()) {
+        return Err("Your password must contain at least one letter".to_string());
+    }
+    if policy.require_number && !password.chars().any(|c| c.is_numeric()) {
+        return Err("Your password must contain at least one number".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[tokio::test]
+    async fn totp_codes_cannot_be_replayed() {
+        let pool = test_pool().await;
+        let user_id: i32 = sqlx::query(
+            "INSERT INTO users(email,username,hashed_password,active,totp_secret,totp_enabled) values('a@b.com','alice','',1,?,1) RETURNING id",
+        )
+        .bind(SECRET)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .try_get("id")
+        .unwrap();
+
+        let code = totp::code_at(SECRET, utilities::get_epoch_time());
+        assert!(verify_totp(user_id, &code, pool.clone()).await);
+        assert!(!verify_totp(user_id, &code, pool.clone()).await);
+    }
+
+    #[tokio::test]
+    async fn totp_is_refused_when_not_enabled() {
+        let pool = test_pool().await;
+        let user_id: i32 = sqlx::query(
+            "INSERT INTO users(email,username,hashed_password,active,totp_secret,totp_enabled) values('a@b.com','alice','',1,?,0) RETURNING id",
+        )
+        .bind(SECRET)
+        .fetch_one(&pool)
+        .await
+        .unwrap()
+        .try_get("id")
+        .unwrap();
+
+        let code = totp::code_at(SECRET, utilities::get_epoch_time());
+        assert!(!verify_totp(user_id, &code, pool).await);
     }
 }
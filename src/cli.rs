@@ -0,0 +1,302 @@
+use crate::email_templates::Locale;
+use crate::{auth_and_login, config::AppConfig, route_handlers, tables, utilities};
+use sqlx::{Row, SqlitePool};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+const USAGE: &str = "Usage: christmas_lists admin <command>
+
+Commands:
+  create-user <username> <email> [--admin] [--inactive]
+                              Create a user, reading the password from standard input
+  activate <username>         Let the user log in
+  deactivate <username>       Stop the user logging in and end their sessions
+  set-password <username>     Set the user's password, reading it from standard input
+  set-role <username> <admin|user>
+                              Make the user an admin or a normal user
+  set-locale <username> <locale>
+                              Set the language the user's emails are written in
+  purge-tokens                Delete expired and revoked sessions
+  stats                       Print user and list statistics
+  migrations                  Show which schema migrations have been applied
+  migrate                     Apply any pending schema migrations";
+
+// Run an admin command against the database, returning a message for the user if it failed
+pub async fn run(args: &[String], app_config: &AppConfig, pool: SqlitePool) -> Result<(), String> {
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    // Everything apart from showing the migration status needs an up to date schema
+    if args.as_slice() == ["migrations"] {
+        return migration_status(pool).await;
+    }
+    tables::create(pool.clone()).await;
+
+    match args.as_slice() {
+        ["create-user", username, email, flags @ ..] => {
+            let admin = flags.contains(&"--admin");
+            let active = !flags.contains(&"--inactive");
+            if let Some(flag) = flags
+                .iter()
+                .find(|flag| **flag != "--admin" && **flag != "--inactive")
+            {
+                return Err(format!("Unknown option {}\n\n{}", flag, USAGE));
+            }
+            create_user(username, email, admin, active, app_config, pool).await
+        }
+        ["activate", username] => set_active(username, true, pool).await,
+        ["deactivate", username] => set_active(username, false, pool).await,
+        ["set-password", username] => set_password(username, app_config, pool).await,
+        ["set-role", username, role] => set_role(username, role, pool).await,
+        ["set-locale", username, locale] => set_locale(username, locale, pool).await,
+        ["purge-tokens"] => purge_tokens(pool).await,
+        ["stats"] => stats(pool).await,
+        ["migrate"] => migration_status(pool).await,
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+async fn create_user(
+    username: &str,
+    email: &str,
+    admin: bool,
+    active: bool,
+    app_config: &AppConfig,
+    pool: SqlitePool,
+) -> Result<(), String> {
+    route_handlers::validate_registration(username, email)?;
+    let password = read_password(app_config)?;
+
+    sqlx::query("INSERT INTO users(email,username,hashed_password,active,role) values(?,?,?,?,?)")
+        .bind(email)
+        .bind(username)
+        .bind(auth_and_login::hash_password(password))
+        .bind(active)
+        .bind(if admin { "admin" } else { "user" })
+        .execute(&pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(e) if e.is_unique_violation() => {
+                "That username or email address is already in use".to_string()
+            }
+            e => format!("Failed to create user: {}", e),
+        })?;
+
+    println!(
+        "Created {}{} {}",
+        if active { "" } else { "inactive " },
+        if admin { "admin" } else { "user" },
+        username
+    );
+    Ok(())
+}
+
+async fn set_active(username: &str, active: bool, pool: SqlitePool) -> Result<(), String> {
+    let user_id = get_user_id(username, &pool).await?;
+    sqlx::query("UPDATE users SET active=? WHERE id=?")
+        .bind(active)
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to update user: {}", e))?;
+
+    if active {
+        println!("Activated {}", username);
+    } else {
+        sqlx::query("UPDATE auth_tokens SET revoked=true WHERE user_id=?")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .map_err(|e| format!("Failed to revoke sessions: {}", e))?;
+        sqlx::query("UPDATE api_tokens SET revoked=true WHERE user_id=?")
+            .bind(user_id)
+            .execute(&pool)
+            .await
+            .map_err(|e| format!("Failed to revoke API tokens: {}", e))?;
+        println!("Deactivated {} and ended their sessions", username);
+    }
+    Ok(())
+}
+
+async fn set_password(
+    username: &str,
+    app_config: &AppConfig,
+    pool: SqlitePool,
+) -> Result<(), String> {
+    let user_id = get_user_id(username, &pool).await?;
+    let password = read_password(app_config)?;
+
+    sqlx::query("UPDATE users SET hashed_password=? WHERE id=?")
+        .bind(auth_and_login::hash_password(password))
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to set password: {}", e))?;
+
+    // Same as a password reset, any existing sessions are ended
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE user_id=?")
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to revoke sessions: {}", e))?;
+
+    println!("Set the password for {}", username);
+    Ok(())
+}
+
+async fn set_role(username: &str, role: &str, pool: SqlitePool) -> Result<(), String> {
+    if role != "admin" && role != "user" {
+        return Err("The role must be admin or user".to_string());
+    }
+    let user_id = get_user_id(username, &pool).await?;
+    sqlx::query("UPDATE users SET role=? WHERE id=?")
+        .bind(role)
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to set role: {}", e))?;
+
+    println!("{} is now {}", username, role);
+    Ok(())
+}
+
+async fn set_locale(username: &str, locale: &str, pool: SqlitePool) -> Result<(), String> {
+    let Some(locale) = Locale::parse(locale) else {
+        let supported: Vec<&str> = Locale::ALL.iter().map(|locale| locale.code()).collect();
+        return Err(format!(
+            "The locale must be one of {}",
+            supported.join(", ")
+        ));
+    };
+    let user_id = get_user_id(username, &pool).await?;
+    sqlx::query("UPDATE users SET locale=? WHERE id=?")
+        .bind(locale.code())
+        .bind(user_id)
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to set locale: {}", e))?;
+
+    println!("{}'s emails will be written in {}", username, locale.code());
+    Ok(())
+}
+
+async fn purge_tokens(pool: SqlitePool) -> Result<(), String> {
+    let result = sqlx::query("DELETE FROM auth_tokens WHERE expiry <= ? OR revoked=true")
+        .bind(utilities::get_epoch_time())
+        .execute(&pool)
+        .await
+        .map_err(|e| format!("Failed to purge sessions: {}", e))?;
+
+    println!(
+        "Deleted {} expired or revoked sessions",
+        result.rows_affected()
+    );
+    Ok(())
+}
+
+async fn stats(pool: SqlitePool) -> Result<(), String> {
+    let totals = sqlx::query(
+        "SELECT
+            (SELECT count(*) FROM users) AS users,
+            (SELECT count(*) FROM users WHERE active=1) AS active_users,
+            (SELECT count(*) FROM users WHERE role='admin') AS admins,
+            (SELECT count(*) FROM auth_tokens WHERE expiry > ? AND revoked=false) AS sessions,
+            (SELECT count(*) FROM presents) AS items,
+            (SELECT count(*) FROM presents WHERE taken=true) AS taken_items",
+    )
+    .bind(utilities::get_epoch_time())
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| format!("Failed to get statistics: {}", e))?;
+    let get = |column: &str| -> i64 { totals.try_get(column).unwrap() };
+
+    println!(
+        "Users: {} ({} active, {} admins)",
+        get("users"),
+        get("active_users"),
+        get("admins")
+    );
+    println!("Active sessions: {}", get("sessions"));
+    println!(
+        "Items: {} ({} being bought)",
+        get("items"),
+        get("taken_items")
+    );
+
+    let lists = sqlx::query(
+        "SELECT
+            u.username,
+            count(p.id) AS items,
+            count(CASE WHEN p.taken=true THEN 1 END) AS taken_items
+        FROM
+            users u
+        LEFT JOIN
+            presents p
+        ON
+            p.user_id = u.id
+        GROUP BY
+            u.id
+        ORDER BY
+            lower(u.username)",
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| format!("Failed to get statistics: {}", e))?;
+
+    println!();
+    println!("{:<30} {:>6} {:>6}", "List", "Items", "Taken");
+    for list in lists {
+        let username: String = list.try_get("username").unwrap();
+        let items: i64 = list.try_get("items").unwrap();
+        let taken_items: i64 = list.try_get("taken_items").unwrap();
+        println!("{:<30} {:>6} {:>6}", username, items, taken_items);
+    }
+    Ok(())
+}
+
+async fn migration_status(pool: SqlitePool) -> Result<(), String> {
+    println!(
+        "Schema version {} of {}",
+        tables::current_version(&pool).await,
+        tables::MIGRATIONS.len()
+    );
+    for (migration, applied) in tables::status(&pool).await {
+        println!(
+            "{:>4}  {:<24}  {}",
+            migration.version,
+            applied
+                .map(|applied| format!("applied {}", utilities::format_timestamp(applied)))
+                .unwrap_or_else(|| "pending".to_string()),
+            migration.description
+        );
+    }
+    Ok(())
+}
+
+async fn get_user_id(username: &str, pool: &SqlitePool) -> Result<i32, String> {
+    sqlx::query("SELECT id FROM users WHERE lower(username) = lower(?)")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| format!("Failed to look up user: {}", e))?
+        .map(|row| row.try_get("id").unwrap())
+        .ok_or_else(|| format!("There is no user called {}", username))
+}
+
+// Read a password from standard input and check it against the password policy.
+// Typed passwords aren't echoed, piped ones are read as a line so scripts still work.
+fn read_password(app_config: &AppConfig) -> Result<String, String> {
+    let password = if io::stdin().is_terminal() {
+        rpassword::prompt_password("Password: ")
+            .map_err(|e| format!("Failed to read password: {}", e))?
+    } else {
+        print!("Password: ");
+        io::stdout().flush().unwrap();
+        let mut password = String::new();
+        io::stdin()
+            .lock()
+            .read_line(&mut password)
+            .map_err(|e| format!("Failed to read password: {}", e))?;
+        password.trim_end_matches(['\r', '\n']).to_string()
+    };
+    auth_and_login::check_new_password(&password, &password, &app_config.password_policy)?;
+    Ok(password)
+}
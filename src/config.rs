@@ -1,39 +1,696 @@
-use std::net::SocketAddr;
-
-use std::env;
-
-pub struct AppConfig<'a> {
-    pub addr: SocketAddr,
-    pub file_path: &'a str,
-}
-
-pub fn get_app_config<'a>() -> AppConfig<'a> {
-    let environment = env::var("APP_ENVIRONMENT");
-
-    match environment {
-        Ok(i) => match i.as_str() {
-            "PRODUCTION" => AppConfig {
-                addr: SocketAddr::from(([127, 0, 0, 1], 3003)),
-                file_path: "/srv/http/christmaslist.xyz/assets",
-            },
-            "TEST" => AppConfig {
-                addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
-                file_path: "./assets",
-            },
-            _ => {
-                println!("Please set APP_ENVIRONMENT variable to either PRODUCTION or TEST");
-                AppConfig {
-                    addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
-                    file_path: "./assets",
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::{env, fmt, fs, path::Path};
+use toml::{Table, Value};
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_letter: bool,
+    pub require_number: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl fmt::Display for SameSite {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SameSite::Strict => write!(f, "Strict"),
+            SameSite::Lax => write!(f, "Lax"),
+            SameSite::None => write!(f, "None"),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionConfig {
+    // How long a session lasts without being used
+    pub lifetime_seconds: i64,
+    // Sessions used with less than this long left are extended to the full lifetime
+    pub refresh_threshold_seconds: i64,
+    pub cookie_secure: bool,
+    pub cookie_same_site: SameSite,
+    pub cookie_path: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub max_failures_per_ip: u32,
+    pub max_failures_per_username: u32,
+    // Failures older than this are forgotten
+    pub window_seconds: i64,
+    pub lockout_seconds: i64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatabaseConfig {
+    // A sqlite: URL
+    pub url: String,
+    pub max_connections: u32,
+    // Write-ahead logging lets readers carry on while something is writing
+    pub wal: bool,
+    // How long to wait for a lock before giving up with a busy error
+    pub busy_timeout_seconds: u64,
+    pub foreign_keys: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailTransport {
+    Smtp,
+    // Write emails to files in file_directory
+    File,
+    // Keep emails in memory and print them
+    Memory,
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Upgrade a plain connection, port 587 by default
+    StartTls,
+    // TLS from the start, port 465 by default
+    Tls,
+    // No encryption, port 25 by default
+    None,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailConfig {
+    pub from: String,
+    pub transport: EmailTransport,
+    pub smtp_host: String,
+    // Defaults to the usual port for smtp_security
+    pub smtp_port: Option<u16>,
+    pub smtp_security: SmtpSecurity,
+    // No authentication is used if this is empty
+    pub smtp_username: String,
+    pub smtp_password: String,
+    pub file_directory: String,
+}
+
+// Optional ways of logging in and managing an account that can be turned off
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub registration: bool,
+    pub magic_links: bool,
+    pub passkeys: bool,
+    pub api_tokens: bool,
+}
+
+// An OpenID Connect provider people can log in with
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcProvider {
+    // Used in the login and callback URLs
+    pub name: String,
+    pub display_name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![
+        "openid".to_string(),
+        "email".to_string(),
+        "profile".to_string(),
+    ]
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AppConfig {
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub asset_path: String,
+    pub site_name: String,
+    pub base_url: String,
+    // Passkeys are tied to this domain, which must match base_url
+    pub webauthn_rp_id: String,
+    pub trust_forwarded_for: bool,
+    pub token_secret: String,
+    pub password_policy: PasswordPolicy,
+    pub database: DatabaseConfig,
+    pub email: EmailConfig,
+    pub session: SessionConfig,
+    pub login_rate_limit: RateLimitConfig,
+    pub features: FeatureConfig,
+    #[serde(default)]
+    pub oidc_providers: Vec<OidcProvider>,
+}
+
+impl AppConfig {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.port)
+    }
+
+    // Check the settings make sense together, returning every problem found
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = vec![];
+
+        let host = self
+            .base_url
+            .strip_prefix("https://")
+            .or_else(|| self.base_url.strip_prefix("http://"))
+            .map(|rest| rest.split([':', '/']).next().unwrap_or_default());
+        match host {
+            None => errors.push("base_url must start with http:// or https://".to_string()),
+            Some(host) => {
+                if self.base_url.ends_with('/') {
+                    errors.push("base_url must not end with a /".to_string());
                 }
+                if host != self.webauthn_rp_id
+                    && !host.ends_with(&format!(".{}", self.webauthn_rp_id))
+                {
+                    errors.push(format!(
+                        "webauthn_rp_id {} must be the host of base_url or a parent domain of it",
+                        self.webauthn_rp_id
+                    ));
+                }
+            }
+        }
+        if !Path::new(&self.asset_path).is_dir() {
+            errors.push(format!("asset_path {} is not a directory", self.asset_path));
+        }
+        if self.token_secret.len() < 16 {
+            errors.push("token_secret must be at least 16 characters long".to_string());
+        }
+
+        if self.password_policy.min_length == 0
+            || self.password_policy.min_length > self.password_policy.max_length
+        {
+            errors.push(
+                "password_policy.min_length must be at least 1 and no more than max_length"
+                    .to_string(),
+            );
+        }
+
+        if !self.database.url.starts_with("sqlite:") {
+            errors.push(format!(
+                "database.url must be a sqlite: URL, not {}",
+                self.database.url
+            ));
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections must be at least 1".to_string());
+        }
+
+        if let Err(e) = self.email.from.parse::<lettre::message::Mailbox>() {
+            errors.push(format!("email.from is not a valid address: {}", e));
+        }
+        if self.email.transport == EmailTransport::Smtp && self.email.smtp_host.is_empty() {
+            errors.push("email.smtp_host must be set to send email by SMTP".to_string());
+        }
+        if self.email.transport == EmailTransport::File && self.email.file_directory.is_empty() {
+            errors.push("email.file_directory must be set to write emails to files".to_string());
+        }
+
+        if self.session.lifetime_seconds <= 0 {
+            errors.push("session.lifetime_seconds must be more than 0".to_string());
+        }
+        if self.session.refresh_threshold_seconds < 0
+            || self.session.refresh_threshold_seconds >= self.session.lifetime_seconds
+        {
+            errors.push(
+                "session.refresh_threshold_seconds must be less than session.lifetime_seconds"
+                    .to_string(),
+            );
+        }
+        if self.session.cookie_same_site == SameSite::None && !self.session.cookie_secure {
+            errors.push(
+                "session.cookie_secure must be true when session.cookie_same_site is None"
+                    .to_string(),
+            );
+        }
+        if !self.session.cookie_path.starts_with('/') {
+            errors.push("session.cookie_path must start with a /".to_string());
+        }
+
+        let rate_limit = &self.login_rate_limit;
+        if rate_limit.max_failures_per_ip == 0
+            || rate_limit.max_failures_per_username == 0
+            || rate_limit.window_seconds <= 0
+            || rate_limit.lockout_seconds <= 0
+        {
+            errors.push("login_rate_limit settings must all be more than 0".to_string());
+        }
+
+        for (index, provider) in self.oidc_providers.iter().enumerate() {
+            if provider.name.is_empty()
+                || !provider
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                errors.push(format!(
+                    "OIDC provider name '{}' may only contain letters, numbers, hyphens and underscores",
+                    provider.name
+                ));
             }
+            if self.oidc_providers[..index]
+                .iter()
+                .any(|other| other.name == provider.name)
+            {
+                errors.push(format!(
+                    "There is more than one OIDC provider called {}",
+                    provider.name
+                ));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+// Anyone can read this secret, so outside the unit tests it must be set in the config file or environment
+#[cfg(test)]
+const DEFAULT_TOKEN_SECRET: &str = "insecure-test-token-secret";
+#[cfg(not(test))]
+const DEFAULT_TOKEN_SECRET: &str = "";
+
+pub(crate) fn test_config() -> AppConfig {
+    AppConfig {
+        bind_address: IpAddr::from([127, 0, 0, 1]),
+        port: 3000,
+        asset_path: "./assets".to_string(),
+        site_name: "Halliday Christmas Lists".to_string(),
+        base_url: "http://localhost:3000".to_string(),
+        webauthn_rp_id: "localhost".to_string(),
+        trust_forwarded_for: false,
+        token_secret: DEFAULT_TOKEN_SECRET.to_string(),
+        password_policy: PasswordPolicy {
+            min_length: 8,
+            max_length: 128,
+            require_letter: false,
+            require_number: false,
+        },
+        database: DatabaseConfig {
+            url: "sqlite://christmas_lists.db".to_string(),
+            max_connections: 5,
+            wal: true,
+            busy_timeout_seconds: 5,
+            foreign_keys: true,
+        },
+        email: EmailConfig {
+            from: "Christmas Lists <management@halliday.nz>".to_string(),
+            transport: EmailTransport::Memory,
+            smtp_host: "mail.halliday.nz".to_string(),
+            smtp_port: None,
+            smtp_security: SmtpSecurity::StartTls,
+            smtp_username: "management".to_string(),
+            smtp_password: "".to_string(),
+            file_directory: "./emails".to_string(),
+        },
+        session: SessionConfig {
+            lifetime_seconds: 604800,
+            refresh_threshold_seconds: 302400,
+            cookie_secure: false,
+            cookie_same_site: SameSite::Lax,
+            cookie_path: "/".to_string(),
+        },
+        login_rate_limit: RateLimitConfig {
+            max_failures_per_ip: 20,
+            max_failures_per_username: 5,
+            window_seconds: 900,
+            lockout_seconds: 300,
+        },
+        features: FeatureConfig {
+            registration: true,
+            magic_links: true,
+            passkeys: true,
+            api_tokens: true,
+        },
+        oidc_providers: vec![],
+    }
+}
+
+fn production_config() -> AppConfig {
+    AppConfig {
+        bind_address: IpAddr::from([127, 0, 0, 1]),
+        port: 3003,
+        asset_path: "/srv/http/christmaslist.xyz/assets".to_string(),
+        base_url: "https://christmaslist.xyz".to_string(),
+        webauthn_rp_id: "christmaslist.xyz".to_string(),
+        trust_forwarded_for: true,
+        password_policy: PasswordPolicy {
+            min_length: 10,
+            max_length: 128,
+            require_letter: true,
+            require_number: true,
+        },
+        email: EmailConfig {
+            transport: EmailTransport::Smtp,
+            ..test_config().email
+        },
+        session: SessionConfig {
+            lifetime_seconds: 2592000,
+            refresh_threshold_seconds: 1296000,
+            cookie_secure: true,
+            cookie_same_site: SameSite::Lax,
+            cookie_path: "/".to_string(),
         },
-        Err(_e) => {
-            println!("Please set APP_ENVIRONMENT variable to either PRODUCTION or TEST");
-            AppConfig {
-                addr: SocketAddr::from(([127, 0, 0, 1], 3000)),
-                file_path: "./assets",
+        login_rate_limit: RateLimitConfig {
+            max_failures_per_ip: 20,
+            max_failures_per_username: 5,
+            window_seconds: 900,
+            lockout_seconds: 900,
+        },
+        ..test_config()
+    }
+}
+
+// Environment variables from before the config file, kept working as overrides
+const LEGACY_ENV_OVERRIDES: [(&str, &str); 3] = [
+    ("TOKEN_SECRET", "token_secret"),
+    ("DATABASE_URL", "database.url"),
+    ("SMTP_PASSWORD", "email.smtp_password"),
+];
+
+// Any setting can be overridden with CHRISTMAS_LISTS_ followed by its path, using __ between sections,
+// for example CHRISTMAS_LISTS_PORT or CHRISTMAS_LISTS_DATABASE__URL
+const ENV_PREFIX: &str = "CHRISTMAS_LISTS_";
+
+const USAGE: &str = "Options, which go before any admin command:
+  --config <file>        Read settings from this TOML file instead of christmas_lists.toml
+  --bind <address>       Address to listen on
+  --port <port>          Port to listen on
+  --assets <directory>   Directory to serve the site from
+  --database-url <url>   sqlite: URL of the database
+  --set <key>=<value>    Override any setting, for example --set features.passkeys=false";
+
+// Build the configuration from the defaults for APP_ENVIRONMENT, then the config file, then environment
+// variables, then command line flags. Returns the configuration and the arguments left after the flags.
+pub fn get_app_config(args: &[String]) -> Result<(AppConfig, Vec<String>), String> {
+    // Guessing would mean running production with test settings, such as printing login links
+    let defaults = match env::var("APP_ENVIRONMENT").as_deref() {
+        Ok("PRODUCTION") => production_config(),
+        Ok("TEST") => test_config(),
+        _ => return Err("Please set APP_ENVIRONMENT to either PRODUCTION or TEST".to_string()),
+    };
+
+    // env::vars panics on a name or value that isn't UTF-8, so those are skipped instead
+    let variables: Vec<(String, String)> = env::vars_os()
+        .filter_map(|(variable, value)| {
+            Some((variable.into_string().ok()?, value.into_string().ok()?))
+        })
+        .collect();
+    let flags = parse_flags(args)?;
+
+    // The default file is optional but one that was asked for must exist
+    let config_file = match flags
+        .config_file
+        .or_else(|| env_var(&variables, "CONFIG_FILE"))
+    {
+        Some(path) => Some(path),
+        None if Path::new("christmas_lists.toml").exists() => {
+            Some("christmas_lists.toml".to_string())
+        }
+        None => None,
+    };
+    let contents = match &config_file {
+        Some(path) => Some(
+            fs::read_to_string(path)
+                .map_err(|e| format!("Unable to read config file {}: {}", path, e))?,
+        ),
+        None => None,
+    };
+
+    let app_config = build_app_config(
+        defaults,
+        config_file.as_deref().zip(contents.as_deref()),
+        &variables,
+        &flags.overrides,
+    )?;
+    Ok((app_config, flags.rest))
+}
+
+// What the command line asked for, split from any admin command after it
+struct Flags {
+    config_file: Option<String>,
+    overrides: Vec<(String, String)>,
+    rest: Vec<String>,
+}
+
+fn parse_flags(args: &[String]) -> Result<Flags, String> {
+    let mut flags = Flags {
+        config_file: None,
+        overrides: vec![],
+        rest: vec![],
+    };
+    let mut remaining = args.iter();
+    while let Some(arg) = remaining.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            flags.rest.push(arg.clone());
+            flags.rest.extend(remaining.cloned());
+            break;
+        };
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag, value.to_string()),
+            _ => (
+                flag,
+                remaining
+                    .next()
+                    .ok_or_else(|| format!("--{} needs a value\n\n{}", flag, USAGE))?
+                    .clone(),
+            ),
+        };
+        let key = match flag {
+            "config" => {
+                flags.config_file = Some(value);
+                continue;
+            }
+            "bind" => "bind_address",
+            "port" => "port",
+            "assets" => "asset_path",
+            "database-url" => "database.url",
+            "set" => {
+                let (key, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| format!("--set needs a key=value, not {}", value))?;
+                flags.overrides.push((key.to_string(), value.to_string()));
+                continue;
+            }
+            _ => return Err(format!("Unknown option --{}\n\n{}", flag, USAGE)),
+        };
+        flags.overrides.push((key.to_string(), value));
+    }
+    Ok(flags)
+}
+
+fn env_var(variables: &[(String, String)], name: &str) -> Option<String> {
+    variables
+        .iter()
+        .find(|(variable, _)| variable == name)
+        .map(|(_, value)| value.clone())
+}
+
+// Apply the config file, environment variables and flags over the defaults, then check the result.
+// Takes everything it reads as arguments so it doesn't depend on the process it runs in.
+fn build_app_config(
+    defaults: AppConfig,
+    config_file: Option<(&str, &str)>,
+    variables: &[(String, String)],
+    flag_overrides: &[(String, String)],
+) -> Result<AppConfig, String> {
+    let mut settings = Table::try_from(&defaults).map_err(|e| e.to_string())?;
+
+    if let Some((path, contents)) = config_file {
+        let file: Table =
+            toml::from_str(contents).map_err(|e| format!("Invalid config file {}: {}", path, e))?;
+        merge(&mut settings, file);
+    }
+
+    for (variable, key) in LEGACY_ENV_OVERRIDES {
+        if let Some(value) = env_var(variables, variable) {
+            set_override(&mut settings, key, &value)
+                .map_err(|e| format!("Invalid {}: {}", variable, e))?;
+        }
+    }
+    for (variable, value) in variables {
+        if let Some(key) = variable.strip_prefix(ENV_PREFIX) {
+            set_override(&mut settings, &key.to_lowercase().replace("__", "."), value)
+                .map_err(|e| format!("Invalid {}: {}", variable, e))?;
+        }
+    }
+    for (key, value) in flag_overrides {
+        set_override(&mut settings, key, value).map_err(|e| format!("Invalid --{}: {}", key, e))?;
+    }
+
+    let app_config: AppConfig = Value::Table(settings)
+        .try_into()
+        .map_err(|e| format!("Invalid configuration: {}", e))?;
+    app_config
+        .validate()
+        .map_err(|errors| format!("Invalid configuration:\n  {}", errors.join("\n  ")))?;
+    Ok(app_config)
+}
+
+// Merge the settings from a file over the defaults, replacing whole values other than tables
+fn merge(settings: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (settings.get_mut(&key), value) {
+            (Some(Value::Table(existing)), Value::Table(value)) => merge(existing, value),
+            (_, value) => {
+                settings.insert(key, value);
             }
         }
     }
 }
+
+// Set a dotted key from a string, converting it to the type of the value it replaces
+fn set_override(settings: &mut Table, key: &str, value: &str) -> Result<(), String> {
+    let (path, name) = match key.rsplit_once('.') {
+        Some((path, name)) => (Some(path), name),
+        None => (None, key),
+    };
+    let mut table = settings;
+    for section in path.into_iter().flat_map(|path| path.split('.')) {
+        table = match table.get_mut(section) {
+            Some(Value::Table(table)) => table,
+            _ => return Err(format!("there is no setting called {}", key)),
+        };
+    }
+
+    let value = match table.get(name) {
+        Some(Value::Integer(_)) => Value::Integer(
+            value
+                .parse()
+                .map_err(|_e| format!("{} must be a whole number", key))?,
+        ),
+        Some(Value::Boolean(_)) => Value::Boolean(
+            value
+                .parse()
+                .map_err(|_e| format!("{} must be true or false", key))?,
+        ),
+        Some(Value::Table(_)) | Some(Value::Array(_)) => {
+            return Err(format!("{} can only be set in the config file", key))
+        }
+        Some(_) => Value::String(value.to_string()),
+        // Optional settings that aren't set have no type to go on
+        None => match (value.parse::<i64>(), value.parse::<bool>()) {
+            (Ok(number), _) => Value::Integer(number),
+            (_, Ok(boolean)) => Value::Boolean(boolean),
+            _ => Value::String(value.to_string()),
+        },
+    };
+    table.insert(name.to_string(), value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn variables(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(variable, value)| (variable.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // The test defaults with only these flags applied
+    fn from_flags(args: &[&str]) -> (AppConfig, Vec<String>) {
+        let flags = parse_flags(&strings(args)).unwrap();
+        let app_config = build_app_config(test_config(), None, &[], &flags.overrides).unwrap();
+        (app_config, flags.rest)
+    }
+
+    #[test]
+    fn flags_take_a_separate_or_joined_value() {
+        let (config, rest) = from_flags(&["--port", "4000", "--set", "features.passkeys=false"]);
+        assert_eq!(config.port, 4000);
+        assert!(!config.features.passkeys);
+        assert!(rest.is_empty());
+
+        let (config, rest) = from_flags(&["--port=4000", "--set=features.passkeys=false"]);
+        assert_eq!(config.port, 4000);
+        assert!(!config.features.passkeys);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn flags_stop_at_the_first_command() {
+        let (config, rest) = from_flags(&["--port=4000", "admin", "list-users", "--port=5000"]);
+        assert_eq!(config.port, 4000);
+        assert_eq!(rest, ["admin", "list-users", "--port=5000"]);
+    }
+
+    #[test]
+    fn bad_flags_are_rejected() {
+        assert!(parse_flags(&strings(&["--set=features.passkeys"])).is_err());
+        assert!(parse_flags(&strings(&["--port"])).is_err());
+        assert!(parse_flags(&strings(&["--colour=red"])).is_err());
+    }
+
+    #[test]
+    fn config_file_is_read_from_the_flag() {
+        let flags = parse_flags(&strings(&["--config", "site.toml", "admin"])).unwrap();
+        assert_eq!(flags.config_file.as_deref(), Some("site.toml"));
+        assert_eq!(flags.rest, ["admin"]);
+    }
+
+    #[test]
+    fn later_sources_override_earlier_ones() {
+        let file = "port = 4000\nsite_name = \"From the file\"\n[features]\npasskeys = false\n";
+        let environment = variables(&[
+            ("CHRISTMAS_LISTS_PORT", "5000"),
+            ("CHRISTMAS_LISTS_FEATURES__API_TOKENS", "false"),
+            ("TOKEN_SECRET", "a-secret-from-the-environment"),
+            ("UNRELATED", "ignored"),
+        ]);
+        let flags = [("port".to_string(), "6000".to_string())];
+        let config = build_app_config(
+            test_config(),
+            Some(("site.toml", file)),
+            &environment,
+            &flags,
+        )
+        .unwrap();
+        assert_eq!(config.site_name, "From the file");
+        assert!(!config.features.passkeys);
+        assert!(!config.features.api_tokens);
+        assert_eq!(config.token_secret, "a-secret-from-the-environment");
+        assert_eq!(config.port, 6000);
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        let unknown = variables(&[("CHRISTMAS_LISTS_COLOUR", "red")]);
+        assert!(build_app_config(test_config(), None, &unknown, &[]).is_err());
+
+        let not_a_number = variables(&[("CHRISTMAS_LISTS_PORT", "many")]);
+        assert!(build_app_config(test_config(), None, &not_a_number, &[]).is_err());
+
+        assert!(build_app_config(test_config(), Some(("site.toml", "port = ")), &[], &[]).is_err());
+
+        // Outside the tests there is no default secret
+        let no_secret = AppConfig {
+            token_secret: "".to_string(),
+            ..test_config()
+        };
+        assert!(build_app_config(no_secret, None, &[], &[]).is_err());
+    }
+}
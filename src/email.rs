@@ -0,0 +1,153 @@
+use crate::config::{EmailConfig, EmailTransport, SmtpSecurity};
+use anyhow::Error;
+use axum::async_trait;
+use lettre::{
+    message::MultiPart,
+    transport::smtp::{authentication::Credentials, PoolConfig},
+    AsyncFileTransport, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::sync::{Arc, Mutex};
+
+// A rendered email, sent as plain text with an HTML alternative
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+// Anything capable of delivering an email, so handlers don't need to know about SMTP
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, to: &str, email: &Email) -> Result<(), Error>;
+}
+
+pub async fn send_email(
+    sender: Arc<dyn EmailSender>,
+    to: String,
+    email: Email,
+) -> Result<(), Error> {
+    sender.send(&to, &email).await
+}
+
+// Build the sender for the configured transport, once at startup so SMTP connections are reused
+pub fn build_sender(config: &EmailConfig) -> Result<Arc<dyn EmailSender>, Error> {
+    Ok(match config.transport {
+        EmailTransport::Smtp => Arc::new(SmtpSender::new(config)?),
+        EmailTransport::File => {
+            std::fs::create_dir_all(&config.file_directory)?;
+            Arc::new(FileSender {
+                from: config.from.clone(),
+                transport: AsyncFileTransport::new(&config.file_directory),
+            })
+        }
+        EmailTransport::Memory => Arc::new(MemorySender::default()),
+    })
+}
+
+fn build_message(from: &str, to: &str, email: &Email) -> Result<Message, Error> {
+    Ok(Message::builder()
+        .from(from.parse()?)
+        .reply_to(from.parse()?)
+        .to(to.parse()?)
+        .subject(&email.subject)
+        .multipart(MultiPart::alternative_plain_html(
+            email.text.clone(),
+            email.html.clone(),
+        ))?)
+}
+
+// Sends email through an SMTP relay, sharing a pool of connections between messages
+pub struct SmtpSender {
+    from: String,
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+}
+
+impl SmtpSender {
+    pub fn new(config: &EmailConfig) -> Result<SmtpSender, Error> {
+        let mut builder = match config.smtp_security {
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host)?
+            }
+            SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host)?,
+            // Only for relays on the same machine or network
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host)
+            }
+        }
+        .pool_config(PoolConfig::new().max_size(20));
+
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        if !config.smtp_username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                config.smtp_username.clone(),
+                config.smtp_password.clone(),
+            ));
+        }
+
+        Ok(SmtpSender {
+            from: config.from.clone(),
+            transport: builder.build(),
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for SmtpSender {
+    async fn send(&self, to: &str, email: &Email) -> Result<(), Error> {
+        self.transport
+            .send(build_message(&self.from, to, email)?)
+            .await?;
+        Ok(())
+    }
+}
+
+// Writes each email to a .eml file in a directory instead of sending it, for local development
+pub struct FileSender {
+    from: String,
+    transport: AsyncFileTransport<Tokio1Executor>,
+}
+
+#[async_trait]
+impl EmailSender for FileSender {
+    async fn send(&self, to: &str, email: &Email) -> Result<(), Error> {
+        let id = self
+            .transport
+            .send(build_message(&self.from, to, email)?)
+            .await?;
+        println!(
+            "Wrote email to {} with subject '{}' as {}.eml",
+            to, email.subject, id
+        );
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SentEmail {
+    pub to: String,
+    pub email: Email,
+}
+
+// Keeps emails in memory instead of sending them, for tests and local development
+#[derive(Default)]
+pub struct MemorySender {
+    pub sent: Mutex<Vec<SentEmail>>,
+}
+
+#[async_trait]
+impl EmailSender for MemorySender {
+    async fn send(&self, to: &str, email: &Email) -> Result<(), Error> {
+        println!(
+            "Captured email to {} with subject '{}':\n{}",
+            to, email.subject, email.text
+        );
+        self.sent.lock().unwrap().push(SentEmail {
+            to: to.to_string(),
+            email: email.clone(),
+        });
+        Ok(())
+    }
+}
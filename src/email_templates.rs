@@ -0,0 +1,262 @@
+use crate::config::AppConfig;
+use crate::email::Email;
+use askama::Template;
+
+// Languages emails can be written in. Each user has one, picked from their browser when they register.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Locale {
+    #[default]
+    En,
+    Fr,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 2] = [Locale::En, Locale::Fr];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Locale::En => "en",
+            Locale::Fr => "fr",
+        }
+    }
+
+    // Accepts a bare language or a regional variant such as en-NZ
+    pub fn parse(value: &str) -> Option<Locale> {
+        let language = value.trim().split(['-', '_']).next()?.to_ascii_lowercase();
+        Locale::ALL
+            .into_iter()
+            .find(|locale| locale.code() == language)
+    }
+
+    // The first supported language in an Accept-Language header, which browsers list in order of preference
+    pub fn from_accept_language(header: Option<&str>) -> Locale {
+        header
+            .into_iter()
+            .flat_map(|header| header.split(','))
+            .filter_map(|language| Locale::parse(language.split(';').next().unwrap_or("")))
+            .next()
+            .unwrap_or_default()
+    }
+}
+
+// Every email the site sends, with whatever each one needs beyond the recipient
+pub enum EmailTemplate<'a> {
+    VerifyEmail { token: &'a str },
+    PasswordReset { token: &'a str },
+    LoginLink { token: &'a str },
+}
+
+impl EmailTemplate<'_> {
+    // Where the email's button goes, which is the same in every language
+    fn link(&self, base_url: &str) -> String {
+        match self {
+            EmailTemplate::VerifyEmail { token } => format!("{}/verify?token={}", base_url, token),
+            EmailTemplate::PasswordReset { token } => {
+                format!("{}/reset-password.html?token={}", base_url, token)
+            }
+            EmailTemplate::LoginLink { token } => {
+                format!("{}/magic-link.html?token={}", base_url, token)
+            }
+        }
+    }
+}
+
+// The parts that differ between emails, laid out the same way by the templates in templates/email
+struct Content {
+    subject: String,
+    intro: String,
+    action: &'static str,
+    outro: &'static str,
+}
+
+// Text shared by every email
+struct Layout {
+    greeting: &'static str,
+    fallback: &'static str,
+    footer: &'static str,
+    lists: &'static str,
+}
+
+fn layout(locale: Locale) -> Layout {
+    match locale {
+        Locale::En => Layout {
+            greeting: "Hi",
+            fallback: "If the button doesn't work, copy this link into your browser:",
+            footer: "You're getting this email because you have an account on",
+            lists: "Go to your lists",
+        },
+        Locale::Fr => Layout {
+            greeting: "Bonjour",
+            fallback: "Si le bouton ne fonctionne pas, copiez ce lien dans votre navigateur :",
+            footer: "Vous recevez cet e-mail car vous avez un compte sur",
+            lists: "Voir vos listes",
+        },
+    }
+}
+
+fn content(template: &EmailTemplate, locale: Locale, site_name: &str) -> Content {
+    match (template, locale) {
+        (EmailTemplate::VerifyEmail { .. }, Locale::En) => Content {
+            subject: format!("Verify your {} account", site_name),
+            intro: "Please verify your email address to activate your account.".to_string(),
+            action: "Verify my email",
+            outro: "This link expires in 24 hours.",
+        },
+        (EmailTemplate::VerifyEmail { .. }, Locale::Fr) => Content {
+            subject: format!("Vérifiez votre compte {}", site_name),
+            intro: "Merci de vérifier votre adresse e-mail pour activer votre compte.".to_string(),
+            action: "Vérifier mon adresse",
+            outro: "Ce lien expire dans 24 heures.",
+        },
+        (EmailTemplate::PasswordReset { .. }, Locale::En) => Content {
+            subject: format!("Reset your {} password", site_name),
+            intro: format!(
+                "Someone asked to reset the password for your {} account. If it was you, use the button below to choose a new one.",
+                site_name
+            ),
+            action: "Choose a new password",
+            outro: "This link expires in 1 hour. If you didn't ask for this you can ignore this email.",
+        },
+        (EmailTemplate::PasswordReset { .. }, Locale::Fr) => Content {
+            subject: format!("Réinitialisez votre mot de passe {}", site_name),
+            intro: format!(
+                "Quelqu'un a demandé à réinitialiser le mot de passe de votre compte {}. Si c'était vous, utilisez le bouton ci-dessous pour en choisir un nouveau.",
+                site_name
+            ),
+            action: "Choisir un nouveau mot de passe",
+            outro: "Ce lien expire dans 1 heure. Si vous n'avez rien demandé, vous pouvez ignorer cet e-mail.",
+        },
+        (EmailTemplate::LoginLink { .. }, Locale::En) => Content {
+            subject: format!("Your {} login link", site_name),
+            intro: format!("Use the button below to log in to {}.", site_name),
+            action: "Log in",
+            outro: "This link expires in 15 minutes and can only be used once. If you didn't ask for it you can ignore this email.",
+        },
+        (EmailTemplate::LoginLink { .. }, Locale::Fr) => Content {
+            subject: format!("Votre lien de connexion {}", site_name),
+            intro: format!("Utilisez le bouton ci-dessous pour vous connecter à {}.", site_name),
+            action: "Me connecter",
+            outro: "Ce lien expire dans 15 minutes et ne peut servir qu'une fois. Si vous ne l'avez pas demandé, vous pouvez ignorer cet e-mail.",
+        },
+    }
+}
+
+// What the layouts fill in, the same for the HTML and plain text versions
+struct Message<'a> {
+    locale: Locale,
+    site_name: &'a str,
+    username: &'a str,
+    content: Content,
+    layout: Layout,
+    link: String,
+    lists_link: String,
+}
+
+// Inline styles and tables, since that's all most email clients reliably support
+#[derive(Template)]
+#[template(path = "email/layout.html")]
+struct HtmlLayout<'a> {
+    message: &'a Message<'a>,
+}
+
+#[derive(Template)]
+#[template(path = "email/layout.txt")]
+struct TextLayout<'a> {
+    message: &'a Message<'a>,
+    underline: String,
+}
+
+// Render an email to a user as matching plain text and HTML, both with the shared header and footer
+pub fn render(
+    template: &EmailTemplate,
+    username: &str,
+    locale: Locale,
+    app_config: &AppConfig,
+) -> Email {
+    let site_name = &app_config.site_name;
+    let message = Message {
+        locale,
+        site_name,
+        username,
+        content: content(template, locale, site_name),
+        layout: layout(locale),
+        link: template.link(&app_config.base_url),
+        lists_link: format!("{}/home.html", app_config.base_url),
+    };
+
+    let text = TextLayout {
+        message: &message,
+        underline: "=".repeat(site_name.chars().count()),
+    }
+    .render()
+    .expect("Failed to render email");
+    let html = HtmlLayout { message: &message }
+        .render()
+        .expect("Failed to render email");
+
+    Email {
+        subject: message.content.subject,
+        text,
+        html,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config;
+
+    // Every email in every language, as both text and HTML, compared against the files in src/snapshots
+    #[test]
+    fn emails_match_snapshots() {
+        let app_config = config::test_config();
+        for locale in Locale::ALL {
+            for (name, template) in [
+                (
+                    "verify_email",
+                    EmailTemplate::VerifyEmail { token: "abc123" },
+                ),
+                (
+                    "password_reset",
+                    EmailTemplate::PasswordReset { token: "abc123" },
+                ),
+                ("login_link", EmailTemplate::LoginLink { token: "abc123" }),
+            ] {
+                let email = render(&template, "Alice", locale, &app_config);
+                let name = format!("{}_{}", name, locale.code());
+                insta::assert_snapshot!(
+                    format!("{}_text", name),
+                    format!("Subject: {}\n\n{}", email.subject, email.text)
+                );
+                insta::assert_snapshot!(format!("{}_html", name), email.html);
+            }
+        }
+    }
+
+    #[test]
+    fn usernames_are_escaped_in_html_only() {
+        let email = render(
+            &EmailTemplate::LoginLink { token: "abc123" },
+            "<b>Alice</b>",
+            Locale::En,
+            &config::test_config(),
+        );
+        assert!(email.html.contains("Hi &lt;b&gt;Alice&lt;/b&gt;,"));
+        assert!(!email.html.contains("<b>Alice"));
+        assert!(email.text.contains("Hi <b>Alice</b>,"));
+    }
+
+    #[test]
+    fn locales_come_from_accept_language() {
+        assert_eq!(
+            Locale::from_accept_language(Some("fr-CA,fr;q=0.9,en;q=0.8")),
+            Locale::Fr
+        );
+        assert_eq!(
+            Locale::from_accept_language(Some("de-DE,en-GB;q=0.7")),
+            Locale::En
+        );
+        assert_eq!(Locale::from_accept_language(Some("de")), Locale::En);
+        assert_eq!(Locale::from_accept_language(None), Locale::En);
+    }
+}
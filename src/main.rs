@@ -1,94 +1,134 @@
-use anyhow::Error;
 use axum::{middleware, Router};
-use lettre::{
-    transport::smtp::{
-        authentication::{Credentials, Mechanism},
-        PoolConfig,
-    },
-    Message, SmtpTransport, Transport,
-};
 use sqlx::{
-    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
     SqlitePool,
 };
+use std::{env, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
 use tower_http::services::{ServeDir, ServeFile};
 
 pub mod auth_and_login;
+pub mod cli;
 pub mod config;
+pub mod email;
+pub mod email_templates;
+pub mod oidc;
+pub mod rate_limit;
 pub mod route_handlers;
 pub mod routes;
 pub mod tables;
+pub mod templates;
+#[cfg(test)]
+mod test_support;
+pub mod totp;
 pub mod utilities;
+pub mod webauthn;
 
 #[derive(Clone)]
 pub struct AppState {
     connection_pool: SqlitePool,
-}
-
-fn _send_email(from: &str, to: &str, subject: &str, body: &str) -> Result<(), Error> {
-    let email = Message::builder()
-        .from(from.parse()?)
-        .reply_to(from.parse()?)
-        .to(to.parse()?)
-        .subject(subject)
-        .body(String::from(body))?;
-
-    // Create TLS transport on port 587 with STARTTLS
-    let sender = SmtpTransport::starttls_relay("mail.halliday.nz")?
-        // Add credentials for authentication
-        .credentials(Credentials::new("management".to_owned(), "".to_owned()))
-        // Configure expected authentication mechanism
-        .authentication(vec![Mechanism::Plain])
-        // Connection pool settings
-        .pool_config(PoolConfig::new().max_size(20))
-        .build();
-
-    // Send the email via remote relay
-    let _result = sender.send(&email)?;
-    Ok(())
+    app_config: config::AppConfig,
+    email_sender: Arc<dyn email::EmailSender>,
+    login_limiter: Arc<rate_limit::LoginLimiter>,
+    http_client: reqwest::Client,
 }
 
 #[tokio::main]
 async fn main() {
-    let app_config: config::AppConfig = config::get_app_config();
+    // Anything left after the config flags is an admin command
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (app_config, args) = match config::get_app_config(&args) {
+        Ok(config) => config,
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    };
 
     // Set connection options
-    let connection_options = SqliteConnectOptions::new()
-        .filename("christmas_lists.db")
-        .create_if_missing(true);
+    let database_config = &app_config.database;
+    let connection_options = SqliteConnectOptions::from_str(&database_config.url)
+        .unwrap_or_else(|e| panic!("Invalid database URL {}: {}", database_config.url, e))
+        .create_if_missing(true)
+        .journal_mode(if database_config.wal {
+            SqliteJournalMode::Wal
+        } else {
+            SqliteJournalMode::Delete
+        })
+        .busy_timeout(Duration::from_secs(database_config.busy_timeout_seconds))
+        .foreign_keys(database_config.foreign_keys);
 
     // Create pool
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
+        .max_connections(database_config.max_connections)
         .connect_with(connection_options)
         .await
         .expect("Failed to create connection pool");
 
-    // Create tables
+    // `christmas_lists admin ...` runs a maintenance command instead of the server
+    if args.first().map(|arg| arg.as_str()) == Some("admin") {
+        if let Err(message) = cli::run(&args[1..], &app_config, pool).await {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Apply any migrations the database is missing
     tables::create(pool.clone()).await;
-    let four_o_four = format!("{}/404.html", app_config.file_path);
+
+    let four_o_four = format!("{}/404.html", app_config.asset_path);
     let serve_dir =
-        ServeDir::new(app_config.file_path).not_found_service(ServeFile::new(four_o_four));
+        ServeDir::new(&app_config.asset_path).not_found_service(ServeFile::new(four_o_four));
+
+    let email_sender = email::build_sender(&app_config.email).expect("Failed to set up email");
+
+    let login_limiter = Arc::new(rate_limit::LoginLimiter::new(
+        app_config.login_rate_limit.clone(),
+        Arc::new(rate_limit::SystemClock),
+    ));
 
     let app_state: AppState = AppState {
         connection_pool: pool,
+        app_config: app_config.clone(),
+        email_sender,
+        login_limiter,
+        http_client: reqwest::Client::new(),
     };
 
-    let protected_routes = routes::get_protected_routes().route_layer(
-        middleware::map_request_with_state(app_state.clone(), auth_and_login::auth),
+    let protected_routes = routes::get_protected_routes(&app_config.features).route_layer(
+        middleware::from_fn_with_state(app_state.clone(), auth_and_login::auth),
     );
 
-    let open_routes = routes::get_open_routes().nest_service("/", serve_dir.clone());
+    // The admin check needs the user from the auth middleware so is layered inside it
+    let admin_routes = routes::get_admin_routes()
+        .route_layer(middleware::from_fn(auth_and_login::require_admin))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth_and_login::auth,
+        ));
+
+    let open_routes =
+        routes::get_open_routes(&app_config.features).nest_service("/", serve_dir.clone());
 
     let app = Router::new()
         .merge(protected_routes)
+        .merge(admin_routes)
         .merge(open_routes)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth_and_login::csrf_protection,
+        ))
         .with_state(app_state);
 
-    let listener = tokio::net::TcpListener::bind(app_config.addr)
+    let listener = tokio::net::TcpListener::bind(app_config.addr())
         .await
         .unwrap();
 
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
 }
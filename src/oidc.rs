@@ -0,0 +1,336 @@
+use crate::{config::OidcProvider, utilities};
+use anyhow::{anyhow, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use rand::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// The parts of the provider's discovery document we need
+#[derive(Deserialize)]
+pub struct Discovery {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub nonce: Option<String>,
+    pub email: Option<String>,
+    // Some providers send this as a string rather than a boolean
+    email_verified: Option<serde_json::Value>,
+}
+
+impl IdTokenClaims {
+    pub fn email_verified(&self) -> bool {
+        match &self.email_verified {
+            Some(serde_json::Value::Bool(verified)) => *verified,
+            Some(serde_json::Value::String(verified)) => verified == "true",
+            _ => false,
+        }
+    }
+}
+
+pub async fn discover(
+    client: &reqwest::Client,
+    provider: &OidcProvider,
+) -> Result<Discovery, Error> {
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        provider.issuer.trim_end_matches('/')
+    );
+    let discovery: Discovery = client
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    if discovery.issuer != provider.issuer {
+        return Err(anyhow!(
+            "Discovery document is for issuer {}",
+            discovery.issuer
+        ));
+    }
+    Ok(discovery)
+}
+
+// A random URL safe string, used for the state, nonce and PKCE code verifier
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 32];
+    rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut secret);
+    URL_SAFE_NO_PAD.encode(secret)
+}
+
+// The S256 PKCE challenge for a code verifier
+pub fn code_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+pub fn authorization_url(
+    discovery: &Discovery,
+    provider: &OidcProvider,
+    redirect_uri: &str,
+    state: &str,
+    nonce: &str,
+    code_verifier: &str,
+) -> String {
+    let separator = if discovery.authorization_endpoint.contains('?') {
+        '&'
+    } else {
+        '?'
+    };
+    format!(
+        "{}{}response_type=code&client_id={}&redirect_uri={}&scope={}&state={}&nonce={}&code_challenge={}&code_challenge_method=S256",
+        discovery.authorization_endpoint,
+        separator,
+        utilities::percent_encode(&provider.client_id),
+        utilities::percent_encode(redirect_uri),
+        utilities::percent_encode(&provider.scopes.join(" ")),
+        state,
+        nonce,
+        code_challenge(code_verifier)
+    )
+}
+
+// Swap the authorization code for tokens, returning the ID token
+pub async fn exchange_code(
+    client: &reqwest::Client,
+    discovery: &Discovery,
+    provider: &OidcProvider,
+    code: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+) -> Result<String, Error> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", redirect_uri),
+        ("client_id", &provider.client_id),
+        ("code_verifier", code_verifier),
+    ];
+    if let Some(client_secret) = &provider.client_secret {
+        form.push(("client_secret", client_secret));
+    }
+
+    let response: TokenResponse = client
+        .post(&discovery.token_endpoint)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(response.id_token)
+}
+
+// Check the ID token was signed by the provider for us and for this login attempt
+pub async fn verify_id_token(
+    client: &reqwest::Client,
+    discovery: &Discovery,
+    provider: &OidcProvider,
+    id_token: &str,
+    nonce: &str,
+) -> Result<IdTokenClaims, Error> {
+    let header = jsonwebtoken::decode_header(id_token)?;
+    if !matches!(
+        header.alg,
+        Algorithm::RS256
+            | Algorithm::RS384
+            | Algorithm::RS512
+            | Algorithm::PS256
+            | Algorithm::PS384
+            | Algorithm::PS512
+            | Algorithm::ES256
+            | Algorithm::ES384
+    ) {
+        return Err(anyhow!(
+            "ID token uses unsupported algorithm {:?}",
+            header.alg
+        ));
+    }
+
+    let jwks: JwkSet = client
+        .get(&discovery.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None => jwks.keys.first(),
+    }
+    .ok_or_else(|| anyhow!("No signing key matches the ID token"))?;
+
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_issuer(&[&discovery.issuer]);
+    let claims =
+        jsonwebtoken::decode::<IdTokenClaims>(id_token, &DecodingKey::from_jwk(jwk)?, &validation)?
+            .claims;
+
+    if claims.nonce.as_deref() != Some(nonce) {
+        return Err(anyhow!("ID token nonce does not match"));
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{MockProvider, MOCK_CLIENT_ID, MOCK_SUBJECT};
+    use p256::ecdsa::SigningKey;
+
+    const NONCE: &str = "expected-nonce";
+
+    async fn verify(
+        mock: &MockProvider,
+        claims: &serde_json::Value,
+    ) -> Result<IdTokenClaims, Error> {
+        verify_signed(mock, &MockProvider::sign(claims, &MockProvider::key())).await
+    }
+
+    async fn verify_signed(mock: &MockProvider, id_token: &str) -> Result<IdTokenClaims, Error> {
+        let client = reqwest::Client::new();
+        let provider = mock.provider();
+        let discovery = discover(&client, &provider).await?;
+        verify_id_token(&client, &discovery, &provider, id_token, NONCE).await
+    }
+
+    #[tokio::test]
+    async fn accepts_a_valid_id_token() {
+        let mock = MockProvider::start().await;
+        let claims = verify(&mock, &mock.claims(NONCE)).await.unwrap();
+        assert_eq!(claims.sub, MOCK_SUBJECT);
+        assert!(claims.email_verified());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_nonce_mismatch() {
+        let mock = MockProvider::start().await;
+        assert!(verify(&mock, &mock.claims("other-nonce")).await.is_err());
+
+        let mut claims = mock.claims(NONCE);
+        claims.as_object_mut().unwrap().remove("nonce");
+        assert!(verify(&mock, &claims).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_audience() {
+        let mock = MockProvider::start().await;
+        let mut claims = mock.claims(NONCE);
+        claims["aud"] = "someone-else".into();
+        assert!(verify(&mock, &claims).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_the_wrong_issuer() {
+        let mock = MockProvider::start().await;
+        let mut claims = mock.claims(NONCE);
+        claims["iss"] = "https://evil.example.com".into();
+        assert!(verify(&mock, &claims).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_an_expired_token() {
+        let mock = MockProvider::start().await;
+        let mut claims = mock.claims(NONCE);
+        claims["exp"] = (utilities::get_epoch_time() - 3600).into();
+        assert!(verify(&mock, &claims).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_a_bad_signature() {
+        let mock = MockProvider::start().await;
+
+        // Signed by a key the provider didn't publish, under the published key's ID
+        let other_key = SigningKey::from_slice(&[9u8; 32]).unwrap();
+        let id_token = MockProvider::sign(&mock.claims(NONCE), &other_key);
+        assert!(verify_signed(&mock, &id_token).await.is_err());
+
+        // Signed properly, then the claims changed
+        let id_token = MockProvider::sign(&mock.claims(NONCE), &MockProvider::key());
+        let parts: Vec<&str> = id_token.split('.').collect();
+        let mut claims = mock.claims(NONCE);
+        claims["sub"] = "someone-else".into();
+        let tampered = format!(
+            "{}.{}.{}",
+            parts[0],
+            URL_SAFE_NO_PAD.encode(claims.to_string()),
+            parts[2]
+        );
+        assert!(verify_signed(&mock, &tampered).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn code_exchange_needs_the_pkce_verifier() {
+        let mock = MockProvider::start().await;
+        let client = reqwest::Client::new();
+        let provider = mock.provider();
+        let discovery = discover(&client, &provider).await.unwrap();
+        let redirect_uri = "http://localhost:3000/oidc/mock/callback";
+        let url = authorization_url(
+            &discovery,
+            &provider,
+            redirect_uri,
+            "state",
+            NONCE,
+            "the-code-verifier",
+        );
+        mock.issue(MockProvider::sign(
+            &mock.claims(NONCE),
+            &MockProvider::key(),
+        ));
+
+        let code = mock.log_in(&url).await.unwrap();
+        let exchanged = exchange_code(
+            &client,
+            &discovery,
+            &provider,
+            &code,
+            redirect_uri,
+            "another-code-verifier",
+        )
+        .await;
+        assert!(exchanged.is_err());
+
+        let code = mock.log_in(&url).await.unwrap();
+        let response = client
+            .post(&discovery.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", &code),
+                ("redirect_uri", redirect_uri),
+                ("client_id", MOCK_CLIENT_ID),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+
+        let code = mock.log_in(&url).await.unwrap();
+        let id_token = exchange_code(
+            &client,
+            &discovery,
+            &provider,
+            &code,
+            redirect_uri,
+            "the-code-verifier",
+        )
+        .await
+        .unwrap();
+        verify_id_token(&client, &discovery, &provider, &id_token, NONCE)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{config::RateLimitConfig, utilities};
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicI64, Ordering},
+    Arc, Mutex,
+};
+
+// Source of the current time in epoch seconds, so the limiter can be driven by a fake clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> i64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        utilities::get_epoch_time()
+    }
+}
+
+// A clock that only moves when told to
+#[derive(Default)]
+pub struct MockClock {
+    time: AtomicI64,
+}
+
+impl MockClock {
+    pub fn new(time: i64) -> MockClock {
+        MockClock {
+            time: AtomicI64::new(time),
+        }
+    }
+
+    pub fn advance(&self, seconds: i64) {
+        self.time.fetch_add(seconds, Ordering::SeqCst);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> i64 {
+        self.time.load(Ordering::SeqCst)
+    }
+}
+
+struct Attempts {
+    window_start: i64,
+    failures: u32,
+    locked_until: i64,
+}
+
+// Counts failed logins per IP address and per username and locks out either when they fail too often
+pub struct LoginLimiter {
+    clock: Arc<dyn Clock>,
+    config: RateLimitConfig,
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl LoginLimiter {
+    pub fn new(config: RateLimitConfig, clock: Arc<dyn Clock>) -> LoginLimiter {
+        LoginLimiter {
+            clock,
+            config,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns the number of seconds until another attempt is allowed if either key is locked out
+    pub fn check(&self, ip_address: &str, username: &str) -> Result<(), i64> {
+        let now = self.clock.now();
+        let attempts = self.attempts.lock().unwrap();
+        let wait = [ip_key(ip_address), username_key(username)]
+            .iter()
+            .filter_map(|key| attempts.get(key))
+            .map(|attempt| attempt.locked_until - now)
+            .max()
+            .unwrap_or(0);
+        if wait > 0 {
+            Err(wait)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn record_failure(&self, ip_address: &str, username: &str) {
+        let now = self.clock.now();
+        let mut attempts = self.attempts.lock().unwrap();
+
+        // Forget anything that can no longer affect a lockout
+        let window_seconds = self.config.window_seconds;
+        attempts.retain(|_key, attempt| {
+            attempt.locked_until > now || attempt.window_start + window_seconds > now
+        });
+
+        for (key, max_failures) in [
+            (ip_key(ip_address), self.config.max_failures_per_ip),
+            (
+                username_key(username),
+                self.config.max_failures_per_username,
+            ),
+        ] {
+            let attempt = attempts.entry(key).or_insert(Attempts {
+                window_start: now,
+                failures: 0,
+                locked_until: 0,
+            });
+            if attempt.window_start + window_seconds <= now {
+                attempt.window_start = now;
+                attempt.failures = 0;
+            }
+            attempt.failures += 1;
+            if attempt.failures >= max_failures {
+                attempt.locked_until = now + self.config.lockout_seconds;
+                attempt.window_start = now;
+                attempt.failures = 0;
+            }
+        }
+    }
+
+    // A successful login clears the failures against the username but not the IP address
+    pub fn record_success(&self, username: &str) {
+        self.attempts
+            .lock()
+            .unwrap()
+            .remove(&username_key(username));
+    }
+}
+
+fn ip_key(ip_address: &str) -> String {
+    format!("ip:{}", ip_address)
+}
+
+fn username_key(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> (LoginLimiter, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let config = RateLimitConfig {
+            max_failures_per_ip: 5,
+            max_failures_per_username: 3,
+            window_seconds: 60,
+            lockout_seconds: 300,
+        };
+        (LoginLimiter::new(config, clock.clone()), clock)
+    }
+
+    #[test]
+    fn locks_out_a_username_at_its_threshold() {
+        let (limiter, _clock) = limiter();
+        limiter.record_failure("10.0.0.1", "alice");
+        limiter.record_failure("10.0.0.2", "alice");
+        assert_eq!(limiter.check("10.0.0.3", "alice"), Ok(()));
+
+        limiter.record_failure("10.0.0.3", "alice");
+        assert_eq!(limiter.check("10.0.0.4", "alice"), Err(300));
+        assert_eq!(limiter.check("10.0.0.4", "bob"), Ok(()));
+    }
+
+    #[test]
+    fn locks_out_an_ip_address_at_its_threshold() {
+        let (limiter, _clock) = limiter();
+        for username in ["a", "b", "c", "d"] {
+            limiter.record_failure("10.0.0.1", username);
+        }
+        assert_eq!(limiter.check("10.0.0.1", "e"), Ok(()));
+
+        limiter.record_failure("10.0.0.1", "e");
+        assert_eq!(limiter.check("10.0.0.1", "f"), Err(300));
+        assert_eq!(limiter.check("10.0.0.2", "f"), Ok(()));
+    }
+
+    #[test]
+    fn compares_usernames_case_insensitively() {
+        let (limiter, _clock) = limiter();
+        limiter.record_failure("10.0.0.1", "Alice");
+        limiter.record_failure("10.0.0.2", "ALICE");
+        limiter.record_failure("10.0.0.3", "alice");
+        assert!(limiter.check("10.0.0.4", "aLiCe").is_err());
+    }
+
+    #[test]
+    fn forgets_failures_once_the_window_has_passed() {
+        let (limiter, clock) = limiter();
+        limiter.record_failure("10.0.0.1", "alice");
+        limiter.record_failure("10.0.0.1", "alice");
+        clock.advance(60);
+        limiter.record_failure("10.0.0.1", "alice");
+        assert_eq!(limiter.check("10.0.0.1", "alice"), Ok(()));
+
+        limiter.record_failure("10.0.0.1", "alice");
+        limiter.record_failure("10.0.0.1", "alice");
+        assert!(limiter.check("10.0.0.1", "alice").is_err());
+    }
+
+    #[test]
+    fn lockout_expires() {
+        let (limiter, clock) = limiter();
+        for _ in 0..3 {
+            limiter.record_failure("10.0.0.1", "alice");
+        }
+        clock.advance(299);
+        assert_eq!(limiter.check("10.0.0.1", "alice"), Err(1));
+        clock.advance(1);
+        assert_eq!(limiter.check("10.0.0.1", "alice"), Ok(()));
+    }
+
+    #[test]
+    fn success_clears_the_username_but_not_the_ip_address() {
+        let (limiter, _clock) = limiter();
+        for _ in 0..3 {
+            limiter.record_failure("10.0.0.1", "alice");
+        }
+        limiter.record_success("Alice");
+        assert_eq!(limiter.check("10.0.0.2", "alice"), Ok(()));
+
+        // The IP address still has its three failures, so two more lock it out
+        limiter.record_failure("10.0.0.1", "bob");
+        assert_eq!(limiter.check("10.0.0.1", "carol"), Ok(()));
+        limiter.record_failure("10.0.0.1", "carol");
+        assert_eq!(limiter.check("10.0.0.1", "dave"), Err(300));
+    }
+}
@@ -1,14 +1,19 @@
-use crate::auth_and_login::User;
-use crate::{auth_and_login, utilities, AppState};
+use crate::auth_and_login::{ApiScope, User};
+use crate::config::{OidcProvider, SessionConfig};
+use crate::email_templates::{self, EmailTemplate, Locale};
+use crate::{auth_and_login, email, oidc, templates, totp, utilities, webauthn, AppState};
 use axum::{
-    extract::{Form, Path, State},
+    extract::{ConnectInfo, Form, Path, Query, State},
     http::{HeaderMap, StatusCode},
     response::Html,
+    Json,
 };
-use futures::TryStreamExt;
+use axum_extra::extract::CookieJar;
 use html_escape::encode_text;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use sqlx::Row;
+use std::net::SocketAddr;
 
 #[derive(Serialize, Deserialize)]
 pub struct Item {
@@ -35,15 +40,153 @@ pub struct RegistrationRequest {
     pub confirm_password: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct VerifyEmailRequest {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ResetPasswordRequest {
+    pub token: String,
+    pub password: String,
+    pub confirm_password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkRequest {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MagicLinkLoginRequest {
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OidcProviderRequest {
+    pub provider: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TotpLoginRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EnableTotpRequest {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisableTotpRequest {
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyRegistration {
+    pub name: String,
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PasskeyLogin {
+    pub credential_id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DeletePasskeyRequest {
+    pub passkey_id: i32,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Passkey {
+    pub id: i32,
+    pub name: Option<String>,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeSessionRequest {
+    pub session_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scope: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RevokeApiTokenRequest {
+    pub token_id: i32,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub scope: String,
+    pub created: i64,
+    pub last_used: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminUserRequest {
+    pub user_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AdminRenameRequest {
+    pub username: String,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct AdminUser {
+    pub id: i32,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub active: bool,
+    pub locale: String,
+    pub sessions: i32,
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Session {
+    pub id: i32,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created: Option<i64>,
+    pub last_seen: Option<i64>,
+    pub current: bool,
+}
+
 #[derive(Serialize, Deserialize, sqlx::FromRow)]
 pub struct Present {
-    id: i32,
-    name: String,
-    url: String,
-    price: String,
-    taken: bool,
+    pub id: i32,
+    pub name: String,
+    pub url: String,
+    pub price: String,
+    pub taken: bool,
     #[sqlx(rename = "username")]
-    taken_by_name: Option<String>,
+    pub taken_by_name: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -60,10 +203,22 @@ pub struct UpdatePasswordRequest {
 
 pub async fn process_login(
     State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
     form_data: Form<auth_and_login::LoginRequest>,
 ) -> (HeaderMap, Html<String>) {
     let mut headers = HeaderMap::new();
     let response_html;
+
+    let ip_address =
+        utilities::get_client_ip(&request_headers, addr, state.app_config.trust_forwarded_for);
+
+    // Refuse before checking the password so repeated guesses don't cost an Argon2 hash each
+    if let Err(wait_seconds) = state.login_limiter.check(&ip_address, &form_data.username) {
+        headers.insert("HX-Retarget", "#login-response".parse().unwrap());
+        return (headers, Html(too_many_attempts_message(wait_seconds)));
+    }
+
     match auth_and_login::verify_login(
         form_data.username.as_str(),
         form_data.password.as_str(),
@@ -72,32 +227,33 @@ pub async fn process_login(
     .await
     {
         Some(value) => {
-            response_html = "".to_string();
-
-            let token = auth_and_login::generate_token();
-            let cookie_duration_in_seconds: i64 = 3600000;
-            let current_time: i64 = utilities::get_epoch_time();
-            let expiry: i64 = current_time + cookie_duration_in_seconds;
-
-            sqlx::query("INSERT INTO auth_tokens (token,user_id,expiry,revoked) values(?,?,?,?)")
-                .bind(&token)
-                .bind(value.id)
-                .bind(expiry)
-                .bind(false)
-                .execute(&state.connection_pool)
-                .await
-                .expect("Failed to create access token");
+            if auth_and_login::totp_enabled(value.id, state.connection_pool.clone()).await {
+                // Failures are only cleared once the second factor has been given too
+                headers.insert(
+                    "Set-Cookie",
+                    start_pending_login(&state, value.id).await.parse().unwrap(),
+                );
+                headers.insert("HX-Retarget", "#login-response".parse().unwrap());
+                response_html = templates::render(&templates::LoginTotp).0;
+            } else {
+                response_html = "".to_string();
+                state.login_limiter.record_success(&form_data.username);
 
-            headers.insert(
-                "Set-Cookie",
-                format!("auth_token={}; Max-Age={}; HttpOnly", &token, expiry)
-                    .parse()
-                    .unwrap(),
-            );
-            headers.insert("HX-Location", "./home.html".parse().unwrap());
+                headers.insert(
+                    "Set-Cookie",
+                    start_session(&state, value.id, &request_headers, &ip_address)
+                        .await
+                        .parse()
+                        .unwrap(),
+                );
+                headers.insert("HX-Location", "./home.html".parse().unwrap());
+            }
         }
         None => {
             response_html = "Invalid username or password".to_string();
+            state
+                .login_limiter
+                .record_failure(&ip_address, &form_data.username);
 
             headers.insert("HX-Retarget", "#login-response".parse().unwrap());
         }
@@ -105,237 +261,2609 @@ pub async fn process_login(
     (headers, Html(response_html))
 }
 
-// pub async fn register(State(state): State<AppState>, Form(form_data): Form<RegistrationRequest>) {
-//     if form_data.password != form_data.confirm_password {
-//         panic!("Passwords do not match");
-//     }
-//     sqlx::query("INSERT INTO users(email,username,hashed_password) values(?,?,?)")
-//         .bind(form_data.email)
-//         .bind(form_data.username)
-//         .bind(auth_and_login::hash_password(form_data.password))
-//         .execute(&state.connection_pool)
-//         .await
-//         .expect("Failed to create registration");
-// }
-
-pub async fn add_item(
+// Second login step for users with two-factor authentication turned on
+pub async fn process_totp_login(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    Form(form_data): Form<Item>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    jar: CookieJar,
+    Form(form_data): Form<TotpLoginRequest>,
 ) -> (HeaderMap, Html<String>) {
-    let user_id = utilities::get_user_id_from_header(headers);
-    let mut response_headers = HeaderMap::new();
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Retarget", "#login-response".parse().unwrap());
 
-    let new_row = sqlx::query(
-        "INSERT INTO presents (user_id,name,url,price,taken) values(?,?,?,?,false) RETURNING id",
+    let ip_address =
+        utilities::get_client_ip(&request_headers, addr, state.app_config.trust_forwarded_for);
+    let pending_token = jar
+        .get("pending_login")
+        .map(|cookie| cookie.value().to_string())
+        .unwrap_or_default();
+
+    let Some(user) = auth_and_login::get_pending_login(
+        &pending_token,
+        &state.app_config.token_secret,
+        state.connection_pool.clone(),
     )
-    .bind(user_id)
-    .bind(&form_data.name)
-    .bind(&form_data.url)
-    .bind(utilities::format_currency(form_data.price))
-    .fetch_one(&state.connection_pool)
     .await
-    .expect("Failed to add item to list.");
+    else {
+        return (
+            headers,
+            Html("Your login has expired, please enter your password again".to_string()),
+        );
+    };
 
-    response_headers.insert("HX-Trigger-After-Swap", "somePresents".parse().unwrap());
+    if let Err(wait_seconds) = state.login_limiter.check(&ip_address, &user.username) {
+        return (headers, Html(too_many_attempts_message(wait_seconds)));
+    }
 
-    let created_id: i32 = new_row.try_get("id").unwrap();
+    let verified =
+        auth_and_login::verify_totp(user.id, &form_data.code, state.connection_pool.clone()).await
+            || auth_and_login::use_recovery_code(
+                user.id,
+                &form_data.code,
+                &state.app_config.token_secret,
+                state.connection_pool.clone(),
+            )
+            .await;
+    if !verified {
+        state
+            .login_limiter
+            .record_failure(&ip_address, &user.username);
+        return (headers, Html("Invalid authentication code".to_string()));
+    }
+
+    if !auth_and_login::complete_pending_login(
+        &pending_token,
+        &state.app_config.token_secret,
+        state.connection_pool.clone(),
+    )
+    .await
+    {
+        return (
+            headers,
+            Html("Your login has expired, please enter your password again".to_string()),
+        );
+    }
+    state.login_limiter.record_success(&user.username);
 
-    (response_headers,Html(format!("<tr><td><a href='{}'>{}</a></td><td>{}</td><td style='text-align:center'><i class='fa-regular fa-x'></i></td><td><a href='#' hx-delete='./item/{}' hx-target='closest tr' hx-swap='outerHTML' hx-confirm='Please confirm you wish to delete {} from your list'><i class=\"fa-duotone fa-trash-can\"></i></a></td></tr>\n",
-                form_data.url, form_data.name, utilities::format_currency(form_data.price),created_id,form_data.name)))
+    let mut headers = HeaderMap::new();
+    headers.append(
+        "Set-Cookie",
+        start_session(&state, user.id, &request_headers, &ip_address)
+            .await
+            .parse()
+            .unwrap(),
+    );
+    headers.append(
+        "Set-Cookie",
+        auth_and_login::build_cookie("pending_login", "", 0, &state.app_config.session)
+            .parse()
+            .unwrap(),
+    );
+    headers.insert("HX-Location", "./home.html".parse().unwrap());
+    (headers, Html("".to_string()))
 }
 
-pub async fn delete_item(
-    State(state): State<AppState>,
-    headers: HeaderMap,
-    delete_request: Path<DeleteRequest>,
-) -> Html<String> {
-    let user_id = utilities::get_user_id_from_header(headers);
-    sqlx::query("DELETE FROM presents WHERE id=? AND user_id=?")
-        .bind(delete_request.item_id)
-        .bind(user_id)
-        .execute(&state.connection_pool)
-        .await
-        .expect("Failed to delete item from list.");
+// Create a session for a user who has logged in and return the Set-Cookie value for it
+async fn start_session(
+    state: &AppState,
+    user_id: i32,
+    request_headers: &HeaderMap,
+    ip_address: &str,
+) -> String {
+    let user_agent = request_headers
+        .get("User-Agent")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let token = auth_and_login::create_session(
+        user_id,
+        user_agent,
+        ip_address,
+        &state.app_config.token_secret,
+        &state.app_config.session,
+        state.connection_pool.clone(),
+    )
+    .await;
 
-    Html("".to_string())
+    auth_and_login::session_cookie(
+        &token,
+        state.app_config.session.lifetime_seconds,
+        &state.app_config.session,
+    )
 }
 
-pub async fn get_items(
+// Remember a user still needs to give their second factor and return the Set-Cookie value for it
+async fn start_pending_login(state: &AppState, user_id: i32) -> String {
+    let pending_token = auth_and_login::create_pending_login(
+        user_id,
+        &state.app_config.token_secret,
+        state.connection_pool.clone(),
+    )
+    .await;
+
+    auth_and_login::build_cookie(
+        "pending_login",
+        &pending_token,
+        300,
+        &state.app_config.session,
+    )
+}
+
+pub async fn request_magic_link(
     State(state): State<AppState>,
-    headers: HeaderMap,
-    items_request: Path<GetItemsRequest>,
+    Form(form_data): Form<MagicLinkRequest>,
 ) -> (HeaderMap, Html<String>) {
-    let mut response_headers = HeaderMap::new();
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Retarget", "#magic-link-response".parse().unwrap());
 
-    let user_id = utilities::get_user_id_from_header(headers);
-    let requested_user_id = match items_request.user_id {
-        Some(i) => i,
-        None => user_id,
-    };
-    let mut presents = sqlx::query_as::<_, Present>(
-        "SELECT
-            p.id,
-            p.name,
-            p.url,
-            p.price,
-            p.taken,
-            u.username
-        FROM
-            presents p
-        LEFT JOIN
-            users u
-        ON
-            p.taken_by_id = u.id
-        WHERE
-            user_id=?",
+    request_emailed_link(&state, EmailedLink::LoginLink, &form_data.email).await;
+    (
+        headers,
+        Html("If that email address belongs to an account, a login link is on its way".to_string()),
     )
-    .bind(requested_user_id)
-    .fetch(&state.connection_pool);
+}
 
-    let mut res = String::from("<table id='list-table'>");
-    if user_id == requested_user_id {
-        response_headers.insert("HX-Trigger", "showAddForm".parse().unwrap());
-        res.push_str(
-            "<thead><th>Name</th><th>Price</th><th>Taken</th><th>Delete</th></tr></thead>\n<tbody>",
+// The emailed link opens a page that posts the token here, so link scanners that follow it don't use it up
+pub async fn process_magic_link(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    Form(form_data): Form<MagicLinkLoginRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+
+    let user_id = redeem_emailed_token(&state, "login_links", &form_data.token).await;
+    let active = match user_id {
+        Some(user_id) => sqlx::query("SELECT id FROM users WHERE id=? AND active=1")
+            .bind(user_id)
+            .fetch_optional(&state.connection_pool)
+            .await
+            .expect("Failed to look up user")
+            .is_some(),
+        None => false,
+    };
+    let (Some(user_id), true) = (user_id, active) else {
+        headers.insert("HX-Retarget", "#login-response".parse().unwrap());
+        return (
+            headers,
+            Html("This login link is invalid or has expired".to_string()),
         );
-    } else {
-        response_headers.insert("HX-Trigger", "hideAddForm".parse().unwrap());
-        res.push_str(
-            "<thead><th>Name</th><th>Price</th><th>Taken</th><th class='taken-by'>Taken by</th><th>Action</th></tr></thead>\n",
+    };
+
+    // The link replaces the password, not the second factor
+    if auth_and_login::totp_enabled(user_id, state.connection_pool.clone()).await {
+        headers.insert(
+            "Set-Cookie",
+            start_pending_login(&state, user_id).await.parse().unwrap(),
         );
+        headers.insert("HX-Location", "./verify-login.html".parse().unwrap());
+        return (headers, Html("".to_string()));
     }
-    let mut row_count = 0;
-    while let Some(row) = presents.try_next().await.unwrap() {
-        row_count += 1;
-        let taken: String = if row.taken {
-            "<i class='fa-regular fa-check'></i>".to_string()
-        } else {
-            "<i class='fa-regular fa-x'></i>".to_string()
-        };
-        if user_id == requested_user_id {
-            res = format!(
-                "{}<tr><td><a href='{}'>{}</a></td><td>{}</td><td style='text-align:center'>{}</td><td><a href='#' hx-target='closest tr' hx-swap='outerHTML' hx-delete='./item/{}' hx-confirm='Please confirm you wish to delete {} from your list'><i class=\"fa-duotone fa-trash-can\"></i></a></td></tr>\n",
-                res, encode_text(&row.url), encode_text(&row.name), encode_text(&row.price), taken,row.id,encode_text(&row.name)
-            );
-        } else {
-            let buying_it_text: String = if row.taken {
-                "".to_string()
-            } else {
-                "<i class='fa-duotone fa-solid fa-cart-plus'></i>".to_string()
-            };
-            res = format!(
-                "{}<tr><td><a href='{}'>{}</a></td><td>{}</td><td style='text-align:center'>{}</td><td class='taken-by'>{}</td><td><a hx-patch='./item/{}' hx-confirm='Please confirm you are buying or have bought {}' hx-target='closest tr' href='#'>{}</a></td></tr>\n",
-                res, encode_text(&row.url), encode_text(&row.name), encode_text(&row.price), taken, encode_text(&row.taken_by_name.unwrap_or_default()),row.id,encode_text(&row.name), buying_it_text
-            );
-        }
-    }
-    res.push_str("</tbody></table>");
-    if row_count == 0 {
-        response_headers.insert("HX-Trigger-After-Swap", "noPresents".parse().unwrap());
-        if user_id != requested_user_id {
-            res.push_str("<p class='no-presents'>This person's list is currently empty.</p>");
-        } else {
-            res.push_str(
-                "<p class='no-presents'>You have no items in your list, try adding some below.</p>",
-            );
-        }
-    } else {
-        response_headers.insert("HX-Trigger-After-Swap", "somePresents".parse().unwrap());
-    }
-    (response_headers, Html(res))
-}
-
-pub async fn get_users(State(state): State<AppState>, headers: HeaderMap) -> Html<String> {
-    let calling_user: User = auth_and_login::get_user(
-        utilities::get_user_id_from_header(headers),
-        state.connection_pool.to_owned(),
-    )
-    .await
-    .expect("Unable to fetch user");
 
-    let mut users_list = format!(
-        "<select hx-target='#items' hx-get='./items/' hx-on='htmx:configRequest: event.detail.path += this.value' id='users-list' name='users-list'><option value='{}'>Your list</option>",
-        calling_user.id
+    let ip_address =
+        utilities::get_client_ip(&request_headers, addr, state.app_config.trust_forwarded_for);
+    headers.insert(
+        "Set-Cookie",
+        start_session(&state, user_id, &request_headers, &ip_address)
+            .await
+            .parse()
+            .unwrap(),
     );
-    let mut rows = sqlx::query("SELECT username,id FROM users ORDER by username ASC")
-        .fetch(&state.connection_pool);
-
-    while let Some(row) = rows.try_next().await.unwrap() {
-        let username: &str = row.try_get("username").unwrap();
-        let user_id: i32 = row.try_get("id").unwrap();
-        if user_id == calling_user.id {
-        } else {
-            users_list = format!(
-                "{}<option value='{}'>{}</option>",
-                users_list, user_id, username
-            );
-        }
-    }
+    headers.insert("HX-Location", "./home.html".parse().unwrap());
+    (headers, Html("".to_string()))
+}
 
-    users_list.push_str("</select>");
+// The ways of logging in other than a password, depending on what is turned on
+pub async fn get_login_options(State(state): State<AppState>) -> Html<String> {
+    templates::render(&templates::LoginOptions {
+        features: &state.app_config.features,
+        providers: &state.app_config.oidc_providers,
+    })
+}
+
+pub async fn start_oidc_login(
+    State(state): State<AppState>,
+    Path(request): Path<OidcProviderRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let (location, cookie) = match begin_oidc_login(&state, &request.provider, None).await {
+        Ok(login) => login,
+        Err(error) => return error,
+    };
 
-    Html(users_list)
+    let mut headers = HeaderMap::new();
+    headers.insert("Location", location.parse().unwrap());
+    headers.insert("Set-Cookie", cookie.parse().unwrap());
+    (StatusCode::SEE_OTHER, headers, Html("".to_string()))
 }
 
-pub async fn allocate_item(
+// A logged in user linking an external account to theirs. Requested with HTMX so it goes through the CSRF checks.
+pub async fn start_oidc_link(
     State(state): State<AppState>,
-    allocated_item: Path<AllocateItemRequest>,
-    headers: HeaderMap,
-) -> Html<String> {
-    let user_id = utilities::get_user_id_from_header(headers);
-    let user = auth_and_login::get_user(user_id, state.connection_pool.clone())
+    user: User,
+    Path(request): Path<OidcProviderRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let (location, cookie) = match begin_oidc_login(&state, &request.provider, Some(user.id)).await
+    {
+        Ok(login) => login,
+        Err(error) => return error,
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Redirect", location.parse().unwrap());
+    headers.insert("Set-Cookie", cookie.parse().unwrap());
+    (StatusCode::OK, headers, Html("".to_string()))
+}
+
+// Store a new login attempt, returning the provider's authorization URL and the cookie tying the attempt to this browser
+async fn begin_oidc_login(
+    state: &AppState,
+    provider_name: &str,
+    linking_user: Option<i32>,
+) -> Result<(String, String), (StatusCode, HeaderMap, Html<String>)> {
+    let Some(provider) = state
+        .app_config
+        .oidc_providers
+        .iter()
+        .find(|provider| provider.name == provider_name)
+    else {
+        return Err(oidc_error(
+            StatusCode::NOT_FOUND,
+            "That login provider isn't set up",
+        ));
+    };
+
+    let discovery = match oidc::discover(&state.http_client, provider).await {
+        Ok(discovery) => discovery,
+        Err(e) => {
+            println!("OIDC discovery for {} failed: {}", provider.name, e);
+            return Err(oidc_error(
+                StatusCode::BAD_GATEWAY,
+                "We couldn't reach that login provider, please try again later",
+            ));
+        }
+    };
+
+    let current_time = utilities::get_epoch_time();
+    sqlx::query("DELETE FROM oidc_logins WHERE expiry < ?")
+        .bind(current_time)
+        .execute(&state.connection_pool)
         .await
-        .expect("Unable to get username");
-    let result = sqlx::query(
-        "UPDATE presents SET taken=true, taken_by_id=? WHERE id=? RETURNING name,url,price",
+        .expect("Failed to remove expired OIDC logins");
+
+    let login_state = oidc::generate_secret();
+    let nonce = oidc::generate_secret();
+    let code_verifier = oidc::generate_secret();
+    sqlx::query(
+        "INSERT INTO oidc_logins (state,provider,nonce,code_verifier,user_id,expiry,used) values(?,?,?,?,?,?,?)",
     )
-    .bind(user_id)
-    .bind(allocated_item.item_id)
-    .fetch_one(&state.connection_pool)
+    .bind(&login_state)
+    .bind(&provider.name)
+    .bind(&nonce)
+    .bind(&code_verifier)
+    .bind(linking_user)
+    .bind(current_time + OIDC_LOGIN_SECONDS)
+    .bind(false)
+    .execute(&state.connection_pool)
     .await
-    .expect("Failed to allocate item.");
-
-    let url: String = result.try_get("url").unwrap();
-    let name: String = result.try_get("name").unwrap();
-    let price: String = result.try_get("price").unwrap();
+    .expect("Failed to store OIDC login");
 
-    Html(format!(
-        "<td><a href='{}'>{}</a></td><td>{}</td><td style='text-align:center'><i class='fa-regular fa-check'></i></td><td>{}</td><td></td>",
-        url, name, price, user.username
+    let location = oidc::authorization_url(
+        &discovery,
+        provider,
+        &oidc_redirect_uri(state, provider),
+        &login_state,
+        &nonce,
+        &code_verifier,
+    );
+    Ok((
+        location,
+        oidc_state_cookie(state, &login_state, OIDC_LOGIN_SECONDS),
     ))
 }
 
-pub async fn update_password(
-    State(_state): State<AppState>,
-    _headers: HeaderMap,
-    Form(request): Form<UpdatePasswordRequest>,
-) -> (StatusCode, Html<String>) {
-    let password = request.password;
-    let confirm_password = request.confirm_password;
-    if password != confirm_password {
-        return (
-            StatusCode::UNPROCESSABLE_ENTITY,
-            Html("Your passwords must match".to_string()),
-        );
+// How long someone has to log in at the provider
+const OIDC_LOGIN_SECONDS: i64 = 600;
+
+// The provider sends the user back with a cross-site redirect, which only brings Lax or None cookies,
+// so the state cookie is always Lax whatever the session cookie uses
+fn oidc_state_cookie(state: &AppState, login_state: &str, max_age: i64) -> String {
+    let mut cookie = format!(
+        "oidc_state={}; Max-Age={}; Path={}; HttpOnly; SameSite=Lax",
+        login_state, max_age, state.app_config.session.cookie_path
+    );
+    if state.app_config.session.cookie_secure {
+        cookie.push_str("; Secure");
     }
-    let _existing_password = request.current_password;
-    (StatusCode::OK, Html("".to_string()))
+    cookie
 }
 
-pub async fn logout(State(_state): State<AppState>) -> (StatusCode, HeaderMap) {
-    let mut headers = HeaderMap::new();
+pub async fn finish_oidc_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    jar: CookieJar,
+    Path(request): Path<OidcProviderRequest>,
+    Query(callback): Query<OidcCallback>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let Some(provider) = state
+        .app_config
+        .oidc_providers
+        .iter()
+        .find(|provider| provider.name == request.provider)
+    else {
+        return oidc_error(StatusCode::NOT_FOUND, "That login provider isn't set up");
+    };
+    let (Some(code), Some(login_state), None) = (callback.code, callback.state, callback.error)
+    else {
+        return oidc_error(
+            StatusCode::BAD_REQUEST,
+            "Logging in was cancelled or failed",
+        );
+    };
+
+    // Only the browser that started the login can finish it, otherwise someone could start one and get
+    // another person to finish it, logging them in as, or linking their account to, the attacker
+    if jar.get("oidc_state").map(|cookie| cookie.value()) != Some(login_state.as_str()) {
+        return oidc_error(
+            StatusCode::BAD_REQUEST,
+            "This login was started in a different browser, please try again",
+        );
+    }
+
+    // Used up as it's read, like the emailed tokens in redeem_emailed_token
+    let login = sqlx::query(
+        "UPDATE oidc_logins SET used=true WHERE state=? AND provider=? AND expiry > ? AND used=false RETURNING nonce,code_verifier,user_id",
+    )
+    .bind(&login_state)
+    .bind(&provider.name)
+    .bind(utilities::get_epoch_time())
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to check OIDC login");
+    let Some(login) = login else {
+        return oidc_error(
+            StatusCode::BAD_REQUEST,
+            "This login has expired, please try again",
+        );
+    };
+    let nonce: String = login.try_get("nonce").unwrap();
+    let code_verifier: String = login.try_get("code_verifier").unwrap();
+    let linking_user: Option<i32> = login.try_get("user_id").unwrap();
+
+    let redirect_uri = oidc_redirect_uri(&state, provider);
+    let claims = async {
+        let discovery = oidc::discover(&state.http_client, provider).await?;
+        let id_token = oidc::exchange_code(
+            &state.http_client,
+            &discovery,
+            provider,
+            &code,
+            &redirect_uri,
+            &code_verifier,
+        )
+        .await?;
+        oidc::verify_id_token(&state.http_client, &discovery, provider, &id_token, &nonce).await
+    }
+    .await;
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => {
+            println!("OIDC login with {} failed: {}", provider.name, e);
+            return oidc_error(
+                StatusCode::BAD_GATEWAY,
+                "We couldn't log you in with that provider, please try again",
+            );
+        }
+    };
 
+    let mut headers = HeaderMap::new();
     headers.insert(
         "Set-Cookie",
-        "auth_token=logout; Max-Age=0; HttpOnly".parse().unwrap(),
+        oidc_state_cookie(&state, "", 0).parse().unwrap(),
     );
 
-    headers.insert("Location", "/index.html".parse().unwrap());
+    if let Some(user_id) = linking_user {
+        // The link must finish in the session that started it
+        let session_user = auth_and_login::validate_cookie(
+            auth_and_login::get_auth_token(&jar),
+            &state.app_config.token_secret,
+            &state.app_config.session,
+            state.connection_pool.clone(),
+        )
+        .await
+        .map(|(user, _extended)| user.id);
+        if session_user != Some(user_id) {
+            return oidc_error(
+                StatusCode::FORBIDDEN,
+                "Please log in again before linking an account",
+            );
+        }
+        return link_oidc_identity(&state, provider, &claims, user_id, headers).await;
+    }
 
-    (StatusCode::TEMPORARY_REDIRECT, headers)
-}
+    let Some(user_id) = find_oidc_user(&state, provider, &claims).await else {
+        return oidc_error(
+            StatusCode::FORBIDDEN,
+            &format!(
+                "There is no Christmas Lists account for your {} account. Register with the same email address first, or log in and link it from your home page.",
+                provider.display_name
+            ),
+        );
+    };
+
+    // The provider replaces the password, not the second factor
+    if auth_and_login::totp_enabled(user_id, state.connection_pool.clone()).await {
+        headers.append(
+            "Set-Cookie",
+            start_pending_login(&state, user_id).await.parse().unwrap(),
+        );
+        headers.insert("Location", "/verify-login.html".parse().unwrap());
+        return (StatusCode::SEE_OTHER, headers, Html("".to_string()));
+    }
+
+    let ip_address =
+        utilities::get_client_ip(&request_headers, addr, state.app_config.trust_forwarded_for);
+    headers.append(
+        "Set-Cookie",
+        start_session(&state, user_id, &request_headers, &ip_address)
+            .await
+            .parse()
+            .unwrap(),
+    );
+    headers.insert("Location", "/home.html".parse().unwrap());
+    (StatusCode::SEE_OTHER, headers, Html("".to_string()))
+}
+
+// Link the external account to the user who started the login, unless it already belongs to someone else
+async fn link_oidc_identity(
+    state: &AppState,
+    provider: &OidcProvider,
+    claims: &oidc::IdTokenClaims,
+    user_id: i32,
+    mut headers: HeaderMap,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    sqlx::query(
+        "INSERT INTO external_identities (provider,subject,user_id,created) values(?,?,?,?) ON CONFLICT(provider,subject) DO NOTHING",
+    )
+    .bind(&provider.name)
+    .bind(&claims.sub)
+    .bind(user_id)
+    .bind(utilities::get_epoch_time())
+    .execute(&state.connection_pool)
+    .await
+    .expect("Failed to link external identity");
+
+    let linked_user: i32 =
+        sqlx::query("SELECT user_id FROM external_identities WHERE provider=? AND subject=?")
+            .bind(&provider.name)
+            .bind(&claims.sub)
+            .fetch_one(&state.connection_pool)
+            .await
+            .expect("Failed to look up external identity")
+            .try_get("user_id")
+            .unwrap();
+    if linked_user != user_id {
+        return oidc_error(
+            StatusCode::CONFLICT,
+            &format!(
+                "That {} account is already linked to a different Christmas Lists account",
+                provider.display_name
+            ),
+        );
+    }
+
+    headers.insert("Location", "/home.html".parse().unwrap());
+    (StatusCode::SEE_OTHER, headers, Html("".to_string()))
+}
+
+// Find the active user linked to the external account, linking it by verified email address the first time
+async fn find_oidc_user(
+    state: &AppState,
+    provider: &OidcProvider,
+    claims: &oidc::IdTokenClaims,
+) -> Option<i32> {
+    let linked = sqlx::query(
+        "SELECT
+            u.id
+        FROM
+            external_identities e
+        JOIN
+            users u
+        ON
+            e.user_id = u.id
+        WHERE
+            e.provider=? AND e.subject=? AND u.active=1",
+    )
+    .bind(&provider.name)
+    .bind(&claims.sub)
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to look up external identity");
+    if let Some(linked) = linked {
+        return Some(linked.try_get("id").unwrap());
+    }
+
+    if !claims.email_verified() {
+        return None;
+    }
+    let user = sqlx::query("SELECT id FROM users WHERE lower(email) = lower(?) AND active=1")
+        .bind(claims.email.as_deref()?)
+        .fetch_optional(&state.connection_pool)
+        .await
+        .expect("Failed to look up user")?;
+    let user_id: i32 = user.try_get("id").unwrap();
+
+    sqlx::query(
+        "INSERT INTO external_identities (provider,subject,user_id,created) values(?,?,?,?)",
+    )
+    .bind(&provider.name)
+    .bind(&claims.sub)
+    .bind(user_id)
+    .bind(utilities::get_epoch_time())
+    .execute(&state.connection_pool)
+    .await
+    .ok()?;
+
+    Some(user_id)
+}
+
+fn oidc_redirect_uri(state: &AppState, provider: &OidcProvider) -> String {
+    format!(
+        "{}/oidc/{}/callback",
+        state.app_config.base_url, provider.name
+    )
+}
+
+fn oidc_error(status: StatusCode, message: &str) -> (StatusCode, HeaderMap, Html<String>) {
+    (
+        status,
+        HeaderMap::new(),
+        templates::render(&templates::OidcError { message }),
+    )
+}
+
+fn too_many_attempts_message(wait_seconds: i64) -> String {
+    let wait_minutes = (wait_seconds + 59) / 60;
+    format!(
+        "Too many failed login attempts, please try again in {} minute{}",
+        wait_minutes,
+        if wait_minutes == 1 { "" } else { "s" }
+    )
+}
+
+pub async fn register(
+    State(state): State<AppState>,
+    request_headers: HeaderMap,
+    Form(form_data): Form<RegistrationRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Retarget", "#register-response".parse().unwrap());
+
+    let username = form_data.username.trim();
+    let email = form_data.email.trim();
+
+    if let Err(message) = validate_registration(username, email) {
+        return (headers, Html(message));
+    }
+    if let Err(message) = auth_and_login::check_new_password(
+        &form_data.password,
+        &form_data.confirm_password,
+        &state.app_config.password_policy,
+    ) {
+        return (headers, Html(message));
+    }
+
+    delete_expired_registrations(&state).await;
+
+    let existing = sqlx::query(
+        "SELECT id FROM users WHERE lower(username) = lower(?) OR lower(email) = lower(?)",
+    )
+    .bind(username)
+    .bind(email)
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to check for existing users");
+    if existing.is_some() {
+        return (
+            headers,
+            Html("That username or email address is already registered".to_string()),
+        );
+    }
+
+    // Emails are written in the first language the browser asks for that we have
+    let locale = Locale::from_accept_language(
+        request_headers
+            .get("Accept-Language")
+            .and_then(|value| value.to_str().ok()),
+    );
+
+    let new_user = match sqlx::query(
+        "INSERT INTO users(email,username,hashed_password,locale) values(?,?,?,?) RETURNING id",
+    )
+    .bind(email)
+    .bind(username)
+    .bind(auth_and_login::hash_password(form_data.password))
+    .bind(locale.code())
+    .fetch_one(&state.connection_pool)
+    .await
+    {
+        Ok(row) => row,
+        Err(_e) => {
+            return (
+                headers,
+                Html("That username or email address is already registered".to_string()),
+            )
+        }
+    };
+    let user_id: i32 = new_user.try_get("id").unwrap();
+
+    let token = auth_and_login::generate_token();
+    let verification_duration_in_seconds: i64 = 86400;
+    let expiry: i64 = utilities::get_epoch_time() + verification_duration_in_seconds;
+
+    sqlx::query("INSERT INTO email_verifications (token_hash,user_id,expiry,used) values(?,?,?,?)")
+        .bind(auth_and_login::hash_token(
+            &token,
+            &state.app_config.token_secret,
+        ))
+        .bind(user_id)
+        .bind(expiry)
+        .bind(false)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to create email verification");
+
+    let message = email_templates::render(
+        &EmailTemplate::VerifyEmail { token: &token },
+        username,
+        locale,
+        &state.app_config,
+    );
+
+    // If sending fails, the registration expires unverified and the address can be registered again
+    send_in_background(&state, email.to_string(), message, "verification");
+
+    (
+        headers,
+        Html("Thanks for registering, please check your email for a verification link".to_string()),
+    )
+}
+
+// Check the registration fields are sensible, returning a message for the user if not
+pub fn validate_registration(username: &str, email: &str) -> Result<(), String> {
+    validate_username(username)?;
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty()
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+                && email.len() <= 100
+                && !email.contains(char::is_whitespace) => {}
+        _ => return Err("Please enter a valid email address".to_string()),
+    }
+    Ok(())
+}
+
+fn validate_username(username: &str) -> Result<(), String> {
+    let username_length = username.chars().count();
+    if username_length == 0 || username_length > 30 {
+        return Err("Your username must be between 1 and 30 characters long".to_string());
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == ' ' || c == '_' || c == '-')
+    {
+        return Err(
+            "Your username may only contain letters, numbers, spaces, hyphens and underscores"
+                .to_string(),
+        );
+    }
+    Ok(())
+}
+
+// Registrations that were never verified give up their username and email once the link expires
+async fn delete_expired_registrations(state: &AppState) {
+    let mut transaction = state
+        .connection_pool
+        .begin()
+        .await
+        .expect("Failed to start transaction");
+    sqlx::query(
+        "DELETE FROM users WHERE active=0 AND id IN (
+            SELECT user_id FROM email_verifications GROUP BY user_id HAVING max(used)=false AND max(expiry) <= ?)",
+    )
+    .bind(utilities::get_epoch_time())
+    .execute(&mut *transaction)
+    .await
+    .expect("Failed to delete expired registrations");
+    sqlx::query("DELETE FROM email_verifications WHERE user_id NOT IN (SELECT id FROM users)")
+        .execute(&mut *transaction)
+        .await
+        .expect("Failed to delete expired email verifications");
+    transaction
+        .commit()
+        .await
+        .expect("Failed to delete expired registrations");
+}
+
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Query(verify_request): Query<VerifyEmailRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+
+    match redeem_emailed_token(&state, "email_verifications", &verify_request.token).await {
+        Some(user_id) => {
+            sqlx::query("UPDATE users SET active=1 WHERE id=?")
+                .bind(user_id)
+                .execute(&state.connection_pool)
+                .await
+                .expect("Failed to activate user");
+
+            headers.insert("Location", "/index.html".parse().unwrap());
+            (
+                StatusCode::TEMPORARY_REDIRECT,
+                headers,
+                Html("".to_string()),
+            )
+        }
+        None => (
+            StatusCode::BAD_REQUEST,
+            headers,
+            Html("This verification link is invalid or has expired".to_string()),
+        ),
+    }
+}
+
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Form(form_data): Form<ForgotPasswordRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Retarget", "#forgot-response".parse().unwrap());
+
+    request_emailed_link(&state, EmailedLink::PasswordReset, &form_data.email).await;
+    (
+        headers,
+        Html(
+            "If that email address belongs to an account, a password reset link is on its way"
+                .to_string(),
+        ),
+    )
+}
+
+// Links emailed to a user on request, each kind kept in its own table of hashed tokens
+#[derive(Clone, Copy)]
+enum EmailedLink {
+    PasswordReset,
+    LoginLink,
+}
+
+impl EmailedLink {
+    fn table(self) -> &'static str {
+        match self {
+            EmailedLink::PasswordReset => "password_resets",
+            EmailedLink::LoginLink => "login_links",
+        }
+    }
+
+    fn lifetime_seconds(self) -> i64 {
+        match self {
+            EmailedLink::PasswordReset => 3600,
+            EmailedLink::LoginLink => 900,
+        }
+    }
+
+    fn template(self, token: &str) -> EmailTemplate<'_> {
+        match self {
+            EmailedLink::PasswordReset => EmailTemplate::PasswordReset { token },
+            EmailedLink::LoginLink => EmailTemplate::LoginLink { token },
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            EmailedLink::PasswordReset => "password reset",
+            EmailedLink::LoginLink => "login link",
+        }
+    }
+}
+
+// Email a link to the active account with this address, if there is one. Nothing is returned so the
+// caller gives the same response whether or not the address is registered.
+async fn request_emailed_link(state: &AppState, link: EmailedLink, email_address: &str) {
+    let user = sqlx::query(
+        "SELECT id,username,email,locale FROM users WHERE lower(email) = lower(?) AND active=1",
+    )
+    .bind(email_address.trim())
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to look up user");
+    let Some(user) = user else {
+        return;
+    };
+    let user_id: i32 = user.try_get("id").unwrap();
+
+    // Don't let the form be used to flood someone's inbox
+    let recent = sqlx::query(&format!(
+        "SELECT id FROM {} WHERE user_id=? AND created > ?",
+        link.table()
+    ))
+    .bind(user_id)
+    .bind(utilities::get_epoch_time() - 60)
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to check for recent links");
+    if recent.is_some() {
+        return;
+    }
+
+    send_emailed_link(
+        state,
+        link,
+        user_id,
+        user.try_get("username").unwrap(),
+        user.try_get("email").unwrap(),
+        user.try_get("locale").unwrap(),
+    )
+    .await;
+}
+
+// Email the user a new link, replacing any they were sent before
+async fn send_emailed_link(
+    state: &AppState,
+    link: EmailedLink,
+    user_id: i32,
+    username: String,
+    email_address: String,
+    locale: String,
+) {
+    // Only the most recently requested link should work
+    sqlx::query(&format!(
+        "UPDATE {} SET used=true WHERE user_id=? AND used=false",
+        link.table()
+    ))
+    .bind(user_id)
+    .execute(&state.connection_pool)
+    .await
+    .expect("Failed to expire old links");
+
+    let token = auth_and_login::generate_token();
+    let current_time: i64 = utilities::get_epoch_time();
+
+    sqlx::query(&format!(
+        "INSERT INTO {} (token_hash,user_id,created,expiry,used) values(?,?,?,?,?)",
+        link.table()
+    ))
+    .bind(auth_and_login::hash_token(
+        &token,
+        &state.app_config.token_secret,
+    ))
+    .bind(user_id)
+    .bind(current_time)
+    .bind(current_time + link.lifetime_seconds())
+    .bind(false)
+    .execute(&state.connection_pool)
+    .await
+    .expect("Failed to create link");
+
+    let message = email_templates::render(
+        &link.template(&token),
+        &username,
+        Locale::parse(&locale).unwrap_or_default(),
+        &state.app_config,
+    );
+    send_in_background(state, email_address, message, link.description());
+}
+
+// Use up an emailed token, returning who it was for if it was valid.
+// Marking the token used in the same statement stops it being redeemed twice.
+async fn redeem_emailed_token(state: &AppState, table: &str, token: &str) -> Option<i32> {
+    sqlx::query(&format!(
+        "UPDATE {} SET used=true WHERE token_hash=? AND expiry > ? AND used=false RETURNING user_id",
+        table
+    ))
+    .bind(auth_and_login::hash_token(
+        token,
+        &state.app_config.token_secret,
+    ))
+    .bind(utilities::get_epoch_time())
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to check emailed token")
+    .map(|row| row.try_get("user_id").unwrap())
+}
+
+// Send without waiting, so neither a slow mail server nor whether an account exists shows in the response time
+fn send_in_background(
+    state: &AppState,
+    email_address: String,
+    message: email::Email,
+    description: &'static str,
+) {
+    let sender = state.email_sender.clone();
+    tokio::spawn(async move {
+        if let Err(e) = email::send_email(sender, email_address, message).await {
+            println!("Failed to send {} email: {}", description, e);
+        }
+    });
+}
+
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Form(form_data): Form<ResetPasswordRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Retarget", "#reset-response".parse().unwrap());
+
+    if let Err(message) = auth_and_login::check_new_password(
+        &form_data.password,
+        &form_data.confirm_password,
+        &state.app_config.password_policy,
+    ) {
+        return (headers, Html(message));
+    }
+
+    let Some(user_id) = redeem_emailed_token(&state, "password_resets", &form_data.token).await
+    else {
+        return (
+            headers,
+            Html("This reset link is invalid or has expired".to_string()),
+        );
+    };
+
+    sqlx::query("UPDATE users SET hashed_password=? WHERE id=?")
+        .bind(auth_and_login::hash_password(form_data.password))
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to update password");
+
+    // Anyone who was logged in with the old password gets signed out
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE user_id=?")
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke sessions");
+
+    headers.insert("HX-Location", "./index.html".parse().unwrap());
+    (headers, Html("".to_string()))
+}
+
+pub async fn add_item(
+    State(state): State<AppState>,
+    user: User,
+    Form(form_data): Form<Item>,
+) -> (HeaderMap, Html<String>) {
+    let user_id = user.id;
+    let mut response_headers = HeaderMap::new();
+
+    if !utilities::is_web_url(&form_data.url) {
+        response_headers.insert("HX-Retarget", "#add-response".parse().unwrap());
+        response_headers.insert("HX-Reswap", "innerHTML".parse().unwrap());
+        return (
+            response_headers,
+            Html("The URL must start with http:// or https://".to_string()),
+        );
+    }
+
+    let new_row = sqlx::query(
+        "INSERT INTO presents (user_id,name,url,price,taken) values(?,?,?,?,false) RETURNING id",
+    )
+    .bind(user_id)
+    .bind(&form_data.name)
+    .bind(&form_data.url)
+    .bind(utilities::format_currency(form_data.price))
+    .fetch_one(&state.connection_pool)
+    .await
+    .expect("Failed to add item to list.");
+
+    response_headers.insert("HX-Trigger-After-Swap", "somePresents".parse().unwrap());
+
+    let item = Present {
+        id: new_row.try_get("id").unwrap(),
+        name: form_data.name,
+        url: form_data.url,
+        price: utilities::format_currency(form_data.price),
+        taken: false,
+        taken_by_name: None,
+    };
+
+    (
+        response_headers,
+        templates::render(&templates::ItemRow { item }),
+    )
+}
+
+pub async fn delete_item(
+    State(state): State<AppState>,
+    user: User,
+    delete_request: Path<DeleteRequest>,
+) -> Html<String> {
+    let user_id = user.id;
+    sqlx::query("DELETE FROM presents WHERE id=? AND user_id=?")
+        .bind(delete_request.item_id)
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to delete item from list.");
+
+    Html("".to_string())
+}
+
+pub async fn get_items(
+    State(state): State<AppState>,
+    user: User,
+    items_request: Path<GetItemsRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut response_headers = HeaderMap::new();
+
+    let user_id = user.id;
+    let requested_user_id = match items_request.user_id {
+        Some(i) => i,
+        None => user_id,
+    };
+    let presents = sqlx::query_as::<_, Present>(
+        "SELECT
+            p.id,
+            p.name,
+            p.url,
+            p.price,
+            p.taken,
+            u.username
+        FROM
+            presents p
+        LEFT JOIN
+            users u
+        ON
+            p.taken_by_id = u.id
+        WHERE
+            user_id=?",
+    )
+    .bind(requested_user_id)
+    .fetch_all(&state.connection_pool)
+    .await
+    .expect("Failed to get items");
+
+    let own_list = user_id == requested_user_id;
+    if own_list {
+        response_headers.insert("HX-Trigger", "showAddForm".parse().unwrap());
+    } else {
+        response_headers.insert("HX-Trigger", "hideAddForm".parse().unwrap());
+    }
+    if presents.is_empty() {
+        response_headers.insert("HX-Trigger-After-Swap", "noPresents".parse().unwrap());
+    } else {
+        response_headers.insert("HX-Trigger-After-Swap", "somePresents".parse().unwrap());
+    }
+
+    (
+        response_headers,
+        templates::render(&templates::Items {
+            own_list,
+            items: presents,
+        }),
+    )
+}
+
+pub async fn get_users(State(state): State<AppState>, calling_user: User) -> Html<String> {
+    let users = sqlx::query(
+        "SELECT username,id FROM users WHERE id != ? AND active=1 ORDER by username ASC",
+    )
+    .bind(calling_user.id)
+    .fetch_all(&state.connection_pool)
+    .await
+    .expect("Failed to get users")
+    .into_iter()
+    .map(|row| (row.try_get("id").unwrap(), row.try_get("username").unwrap()))
+    .collect();
+
+    templates::render(&templates::UsersSelect {
+        own_id: calling_user.id,
+        users,
+    })
+}
+
+pub async fn allocate_item(
+    State(state): State<AppState>,
+    allocated_item: Path<AllocateItemRequest>,
+    user: User,
+) -> Html<String> {
+    let user_id = user.id;
+    let result = sqlx::query(
+        "UPDATE presents SET taken=true, taken_by_id=? WHERE id=? RETURNING name,url,price",
+    )
+    .bind(user_id)
+    .bind(allocated_item.item_id)
+    .fetch_one(&state.connection_pool)
+    .await
+    .expect("Failed to allocate item.");
+
+    let item = Present {
+        id: allocated_item.item_id,
+        name: result.try_get("name").unwrap(),
+        url: result.try_get("url").unwrap(),
+        price: result.try_get("price").unwrap(),
+        taken: true,
+        taken_by_name: Some(user.username),
+    };
+
+    templates::render(&templates::AllocatedItem { item })
+}
+
+pub async fn update_password(
+    State(state): State<AppState>,
+    user: User,
+    jar: CookieJar,
+    Form(request): Form<UpdatePasswordRequest>,
+) -> (StatusCode, Html<String>) {
+    let user_id = user.id;
+
+    if !auth_and_login::verify_password(
+        user_id,
+        &request.current_password,
+        state.connection_pool.clone(),
+    )
+    .await
+    {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Html("Your current password is incorrect".to_string()),
+        );
+    }
+    if let Err(message) = auth_and_login::check_new_password(
+        &request.password,
+        &request.confirm_password,
+        &state.app_config.password_policy,
+    ) {
+        return (StatusCode::UNPROCESSABLE_ENTITY, Html(message));
+    }
+
+    sqlx::query("UPDATE users SET hashed_password=? WHERE id=?")
+        .bind(auth_and_login::hash_password(request.password))
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to update password");
+
+    // Sign out every other session so the old password can't be used to stay logged in
+    let current_token = auth_and_login::get_auth_token(&jar);
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE user_id=? AND token_hash != ?")
+        .bind(user_id)
+        .bind(auth_and_login::hash_token(
+            &current_token,
+            &state.app_config.token_secret,
+        ))
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke sessions");
+
+    (
+        StatusCode::OK,
+        Html("Your password has been updated".to_string()),
+    )
+}
+
+// Links to the account pages on the home page, depending on what is turned on
+pub async fn get_account_links(State(state): State<AppState>, user: User) -> Html<String> {
+    templates::render(&templates::AccountLinks {
+        features: &state.app_config.features,
+        providers: &state.app_config.oidc_providers,
+        admin: user.is_admin(),
+    })
+}
+
+pub async fn get_sessions(
+    State(state): State<AppState>,
+    user: User,
+    jar: CookieJar,
+) -> Html<String> {
+    let user_id = user.id;
+    let current_token = auth_and_login::get_auth_token(&jar);
+
+    let sessions = sqlx::query_as::<_, Session>(
+        "SELECT
+            id,
+            user_agent,
+            ip_address,
+            created,
+            last_seen,
+            token_hash = ? AS current
+        FROM
+            auth_tokens
+        WHERE
+            user_id=? AND expiry > ? AND revoked=false
+        ORDER BY
+            last_seen DESC",
+    )
+    .bind(auth_and_login::hash_token(
+        &current_token,
+        &state.app_config.token_secret,
+    ))
+    .bind(user_id)
+    .bind(utilities::get_epoch_time())
+    .fetch_all(&state.connection_pool)
+    .await
+    .expect("Failed to get sessions");
+
+    templates::render(&templates::Sessions { sessions })
+}
+
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    user: User,
+    revoke_request: Path<RevokeSessionRequest>,
+) -> Html<String> {
+    let user_id = user.id;
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE id=? AND user_id=?")
+        .bind(revoke_request.session_id)
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke session");
+
+    Html("".to_string())
+}
+
+pub async fn get_api_tokens(State(state): State<AppState>, user: User) -> Html<String> {
+    let tokens = sqlx::query_as::<_, ApiToken>(
+        "SELECT
+            id,
+            name,
+            scope,
+            created,
+            last_used
+        FROM
+            api_tokens
+        WHERE
+            user_id=? AND revoked=false
+        ORDER BY
+            created DESC",
+    )
+    .bind(user.id)
+    .fetch_all(&state.connection_pool)
+    .await
+    .expect("Failed to get API tokens");
+
+    templates::render(&templates::ApiTokens { tokens })
+}
+
+pub async fn create_api_token(
+    State(state): State<AppState>,
+    user: User,
+    Form(form_data): Form<CreateApiTokenRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+    headers.insert("HX-Trigger", "apiTokensChanged".parse().unwrap());
+
+    let name = form_data.name.trim();
+    let Some(scope) = ApiScope::parse(&form_data.scope) else {
+        return (
+            headers,
+            Html("Please choose what the token can do".to_string()),
+        );
+    };
+    if name.is_empty() || name.len() > 100 {
+        return (
+            headers,
+            Html("Please give the token a name of up to 100 characters".to_string()),
+        );
+    }
+
+    let token = auth_and_login::create_api_token(
+        user.id,
+        name,
+        scope,
+        &state.app_config.token_secret,
+        state.connection_pool.clone(),
+    )
+    .await;
+
+    (
+        headers,
+        templates::render(&templates::ApiTokenCreated { token }),
+    )
+}
+
+pub async fn revoke_api_token(
+    State(state): State<AppState>,
+    user: User,
+    revoke_request: Path<RevokeApiTokenRequest>,
+) -> Html<String> {
+    sqlx::query("UPDATE api_tokens SET revoked=true WHERE id=? AND user_id=?")
+        .bind(revoke_request.token_id)
+        .bind(user.id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke API token");
+
+    Html("".to_string())
+}
+
+pub async fn get_totp_status(State(state): State<AppState>, user: User) -> Html<String> {
+    templates::render(&templates::TotpStatus {
+        enabled: auth_and_login::totp_enabled(user.id, state.connection_pool.clone()).await,
+    })
+}
+
+pub async fn setup_totp(State(state): State<AppState>, user: User) -> Html<String> {
+    if auth_and_login::totp_enabled(user.id, state.connection_pool.clone()).await {
+        return templates::render(&templates::TotpStatus { enabled: true });
+    }
+
+    // The secret is only used for logins once a code from it has been confirmed
+    let secret = totp::generate_secret();
+    sqlx::query("UPDATE users SET totp_secret=? WHERE id=? AND totp_enabled=0")
+        .bind(&secret)
+        .bind(user.id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to store TOTP secret");
+
+    let uri = totp::provisioning_uri(&secret, &user.username, &state.app_config.site_name);
+    let qr_code_svg = totp::qr_code_svg(&uri).expect("Failed to create QR code");
+
+    templates::render(&templates::TotpSetup {
+        secret,
+        qr_code_svg,
+    })
+}
+
+pub async fn enable_totp(
+    State(state): State<AppState>,
+    user: User,
+    Form(form_data): Form<EnableTotpRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+
+    let query = sqlx::query(
+        "SELECT totp_secret FROM users WHERE id=? AND totp_enabled=0 AND totp_secret IS NOT NULL",
+    )
+    .bind(user.id)
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to get TOTP secret");
+    let Some(row) = query else {
+        return (
+            headers,
+            templates::render(&templates::TotpStatus { enabled: true }),
+        );
+    };
+    let secret: String = row.try_get("totp_secret").unwrap();
+
+    let Some(step) = totp::verify_code(&secret, &form_data.code, utilities::get_epoch_time())
+    else {
+        headers.insert("HX-Retarget", "#totp-response".parse().unwrap());
+        return (
+            headers,
+            Html("That code didn't match, please try again".to_string()),
+        );
+    };
+
+    sqlx::query("UPDATE users SET totp_enabled=1, totp_last_step=? WHERE id=?")
+        .bind(step)
+        .bind(user.id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to enable TOTP");
+
+    let codes = auth_and_login::create_recovery_codes(
+        user.id,
+        &state.app_config.token_secret,
+        state.connection_pool.clone(),
+    )
+    .await;
+
+    (
+        headers,
+        templates::render(&templates::RecoveryCodes { codes }),
+    )
+}
+
+pub async fn disable_totp(
+    State(state): State<AppState>,
+    user: User,
+    Form(form_data): Form<DisableTotpRequest>,
+) -> (HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+
+    if !auth_and_login::verify_password(user.id, &form_data.password, state.connection_pool.clone())
+        .await
+    {
+        headers.insert("HX-Retarget", "#totp-response".parse().unwrap());
+        return (headers, Html("Your password is incorrect".to_string()));
+    }
+
+    sqlx::query(
+        "UPDATE users SET totp_enabled=0, totp_secret=NULL, totp_last_step=NULL WHERE id=?",
+    )
+    .bind(user.id)
+    .execute(&state.connection_pool)
+    .await
+    .expect("Failed to disable TOTP");
+
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id=?")
+        .bind(user.id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to remove recovery codes");
+
+    (
+        headers,
+        templates::render(&templates::TotpStatus { enabled: false }),
+    )
+}
+
+pub async fn start_passkey_registration(
+    State(state): State<AppState>,
+    user: User,
+) -> Json<serde_json::Value> {
+    let challenge = store_webauthn_challenge(&state, Some(user.id), "register").await;
+
+    // Stop the same authenticator being registered twice
+    let existing: Vec<String> =
+        sqlx::query("SELECT credential_id FROM webauthn_credentials WHERE user_id=?")
+            .bind(user.id)
+            .fetch_all(&state.connection_pool)
+            .await
+            .expect("Failed to get passkeys")
+            .iter()
+            .map(|row| row.get("credential_id"))
+            .collect();
+
+    Json(json!({
+        "challenge": challenge,
+        "rp": { "id": state.app_config.webauthn_rp_id, "name": state.app_config.site_name },
+        "user": {
+            "id": webauthn::encode(user.id.to_string().as_bytes()),
+            "name": user.username,
+            "displayName": user.username
+        },
+        "pubKeyCredParams": [
+            { "type": "public-key", "alg": webauthn::ES256 },
+            { "type": "public-key", "alg": webauthn::RS256 }
+        ],
+        "timeout": 300000,
+        "attestation": "none",
+        "authenticatorSelection": {
+            "residentKey": "required",
+            "requireResidentKey": true,
+            "userVerification": "preferred"
+        },
+        "excludeCredentials": existing
+            .iter()
+            .map(|id| json!({ "type": "public-key", "id": id }))
+            .collect::<Vec<_>>()
+    }))
+}
+
+pub async fn finish_passkey_registration(
+    State(state): State<AppState>,
+    user: User,
+    Json(registration): Json<PasskeyRegistration>,
+) -> (StatusCode, Html<String>) {
+    let failed = (
+        StatusCode::BAD_REQUEST,
+        Html("We couldn't add that passkey, please try again".to_string()),
+    );
+
+    let verified = webauthn::decode(&registration.client_data_json).and_then(|client_data| {
+        webauthn::verify_registration(
+            &client_data,
+            &webauthn::decode(&registration.attestation_object)?,
+            &state.app_config.webauthn_rp_id,
+            &state.app_config.base_url,
+        )
+    });
+    let (challenge, credential) = match verified {
+        Ok(verified) => verified,
+        Err(e) => {
+            println!("Passkey registration failed: {}", e);
+            return failed;
+        }
+    };
+
+    if !use_webauthn_challenge(&state, &challenge, Some(user.id), "register").await {
+        return failed;
+    }
+
+    let name = match registration.name.trim() {
+        "" => "Passkey".to_string(),
+        name => name.chars().take(100).collect(),
+    };
+    let current_time = utilities::get_epoch_time();
+    let inserted = sqlx::query(
+        "INSERT INTO webauthn_credentials (user_id,credential_id,public_key,sign_count,name,created) values(?,?,?,?,?,?)",
+    )
+    .bind(user.id)
+    .bind(&credential.credential_id)
+    .bind(&credential.public_key)
+    .bind(credential.sign_count)
+    .bind(name)
+    .bind(current_time)
+    .execute(&state.connection_pool)
+    .await;
+
+    match inserted {
+        Ok(_result) => (
+            StatusCode::OK,
+            Html("Your passkey has been added".to_string()),
+        ),
+        Err(_e) => (
+            StatusCode::CONFLICT,
+            Html("That passkey has already been added".to_string()),
+        ),
+    }
+}
+
+pub async fn get_passkeys(State(state): State<AppState>, user: User) -> Html<String> {
+    let passkeys = sqlx::query_as::<_, Passkey>(
+        "SELECT id,name,created,last_used FROM webauthn_credentials WHERE user_id=? ORDER BY created ASC",
+    )
+    .bind(user.id)
+    .fetch_all(&state.connection_pool)
+    .await
+    .expect("Failed to get passkeys");
+
+    templates::render(&templates::Passkeys { passkeys })
+}
+
+pub async fn delete_passkey(
+    State(state): State<AppState>,
+    user: User,
+    delete_request: Path<DeletePasskeyRequest>,
+) -> Html<String> {
+    sqlx::query("DELETE FROM webauthn_credentials WHERE id=? AND user_id=?")
+        .bind(delete_request.passkey_id)
+        .bind(user.id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to delete passkey");
+
+    Html("".to_string())
+}
+
+pub async fn start_passkey_login(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let challenge = store_webauthn_challenge(&state, None, "authenticate").await;
+
+    // No allowed credentials are listed, so the browser offers any passkey it has for the site
+    Json(json!({
+        "challenge": challenge,
+        "rpId": state.app_config.webauthn_rp_id,
+        "timeout": 300000,
+        "userVerification": "preferred"
+    }))
+}
+
+pub async fn finish_passkey_login(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    Json(login): Json<PasskeyLogin>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+    let failed = (
+        StatusCode::UNAUTHORIZED,
+        HeaderMap::new(),
+        Html("We couldn't log you in with that passkey".to_string()),
+    );
+
+    let credential = sqlx::query(
+        "SELECT
+            c.id,
+            c.user_id,
+            c.public_key,
+            c.sign_count
+        FROM
+            webauthn_credentials c
+        JOIN
+            users u
+        ON
+            c.user_id = u.id
+        WHERE
+            c.credential_id=? AND u.active=1",
+    )
+    .bind(&login.credential_id)
+    .fetch_optional(&state.connection_pool)
+    .await
+    .expect("Failed to look up passkey");
+    let Some(credential) = credential else {
+        return failed;
+    };
+    let passkey_id: i32 = credential.try_get("id").unwrap();
+    let user_id: i32 = credential.try_get("user_id").unwrap();
+    let public_key: Vec<u8> = credential.try_get("public_key").unwrap();
+    let stored_sign_count: u32 = credential.try_get("sign_count").unwrap();
+
+    let verified = (|| {
+        webauthn::verify_authentication(
+            &webauthn::decode(&login.client_data_json)?,
+            &webauthn::decode(&login.authenticator_data)?,
+            &webauthn::decode(&login.signature)?,
+            &public_key,
+            stored_sign_count,
+            &state.app_config.webauthn_rp_id,
+            &state.app_config.base_url,
+        )
+    })();
+    let (challenge, sign_count) = match verified {
+        Ok(verified) => verified,
+        Err(e) => {
+            println!("Passkey login failed: {}", e);
+            return failed;
+        }
+    };
+
+    if !use_webauthn_challenge(&state, &challenge, None, "authenticate").await {
+        return failed;
+    }
+
+    sqlx::query("UPDATE webauthn_credentials SET sign_count=?, last_used=? WHERE id=?")
+        .bind(sign_count)
+        .bind(utilities::get_epoch_time())
+        .bind(passkey_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to update passkey");
+
+    // The passkey replaces the password, not the second factor
+    if auth_and_login::totp_enabled(user_id, state.connection_pool.clone()).await {
+        headers.insert(
+            "Set-Cookie",
+            start_pending_login(&state, user_id).await.parse().unwrap(),
+        );
+        headers.insert("HX-Location", "./verify-login.html".parse().unwrap());
+        return (StatusCode::OK, headers, Html("".to_string()));
+    }
+
+    let ip_address =
+        utilities::get_client_ip(&request_headers, addr, state.app_config.trust_forwarded_for);
+    headers.insert(
+        "Set-Cookie",
+        start_session(&state, user_id, &request_headers, &ip_address)
+            .await
+            .parse()
+            .unwrap(),
+    );
+    headers.insert("HX-Location", "./home.html".parse().unwrap());
+
+    (StatusCode::OK, headers, Html("".to_string()))
+}
+
+// Store a new WebAuthn challenge for five minutes and return it
+async fn store_webauthn_challenge(
+    state: &AppState,
+    user_id: Option<i32>,
+    ceremony: &str,
+) -> String {
+    let current_time = utilities::get_epoch_time();
+
+    // Anyone can ask for a login challenge, so clear out old ones as we go
+    sqlx::query("DELETE FROM webauthn_challenges WHERE expiry < ?")
+        .bind(current_time)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to remove expired challenges");
+
+    let challenge = webauthn::generate_challenge();
+    sqlx::query(
+        "INSERT INTO webauthn_challenges (challenge,user_id,ceremony,expiry,used) values(?,?,?,?,?)",
+    )
+    .bind(&challenge)
+    .bind(user_id)
+    .bind(ceremony)
+    .bind(current_time + 300)
+    .bind(false)
+    .execute(&state.connection_pool)
+    .await
+    .expect("Failed to store challenge");
+
+    challenge
+}
+
+// Mark a challenge as used, returning false if it wasn't issued for this ceremony or has already been used
+async fn use_webauthn_challenge(
+    state: &AppState,
+    challenge: &str,
+    user_id: Option<i32>,
+    ceremony: &str,
+) -> bool {
+    sqlx::query(
+        "UPDATE webauthn_challenges SET used=true WHERE challenge=? AND user_id IS ? AND ceremony=? AND expiry > ? AND used=false",
+    )
+    .bind(challenge)
+    .bind(user_id)
+    .bind(ceremony)
+    .bind(utilities::get_epoch_time())
+    .execute(&state.connection_pool)
+    .await
+    .map(|result| result.rows_affected() == 1)
+    .unwrap_or(false)
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    user: User,
+    jar: CookieJar,
+) -> (StatusCode, HeaderMap) {
+    let user_id = user.id;
+    let token = auth_and_login::get_auth_token(&jar);
+
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE token_hash=? AND user_id=?")
+        .bind(auth_and_login::hash_token(
+            &token,
+            &state.app_config.token_secret,
+        ))
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke session");
+
+    logged_out_response(&state.app_config.session)
+}
+
+pub async fn logout_everywhere(
+    State(state): State<AppState>,
+    user: User,
+) -> (StatusCode, HeaderMap) {
+    let user_id = user.id;
+
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE user_id=?")
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke sessions");
+
+    logged_out_response(&state.app_config.session)
+}
+
+// Clear the auth cookie and send the browser back to the login page.
+// Logging out is requested with HTMX, which would follow a redirect with another POST.
+fn logged_out_response(session_config: &SessionConfig) -> (StatusCode, HeaderMap) {
+    let mut headers = HeaderMap::new();
+
+    headers.insert(
+        "Set-Cookie",
+        auth_and_login::session_cookie("logout", 0, session_config)
+            .parse()
+            .unwrap(),
+    );
+
+    headers.insert("HX-Redirect", "/index.html".parse().unwrap());
+
+    (StatusCode::OK, headers)
+}
+
+const ADMIN_USER_QUERY: &str = "SELECT
+        u.id,
+        u.username,
+        u.email,
+        u.role,
+        u.active,
+        u.locale,
+        (SELECT count(*) FROM auth_tokens a WHERE a.user_id = u.id AND a.expiry > ? AND a.revoked=false) AS sessions
+    FROM
+        users u";
+
+pub async fn admin_get_users(State(state): State<AppState>) -> Html<String> {
+    let query = format!("{} ORDER BY lower(u.username)", ADMIN_USER_QUERY);
+    let users = sqlx::query_as::<_, AdminUser>(&query)
+        .bind(utilities::get_epoch_time())
+        .fetch_all(&state.connection_pool)
+        .await
+        .expect("Failed to get users");
+
+    templates::render(&templates::AdminUsers { users })
+}
+
+async fn get_admin_user(state: &AppState, user_id: i32) -> Option<AdminUser> {
+    sqlx::query_as::<_, AdminUser>(&format!("{} WHERE u.id=?", ADMIN_USER_QUERY))
+        .bind(utilities::get_epoch_time())
+        .bind(user_id)
+        .fetch_optional(&state.connection_pool)
+        .await
+        .expect("Failed to look up user")
+}
+
+// Return the user's refreshed row, or a message in the admin response area if something went wrong
+async fn admin_user_response(
+    state: &AppState,
+    user_id: i32,
+    error: Option<String>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let mut headers = HeaderMap::new();
+    if let Some(error) = error {
+        headers.insert("HX-Retarget", "#admin-response".parse().unwrap());
+        headers.insert("HX-Reswap", "innerHTML".parse().unwrap());
+        return (
+            StatusCode::OK,
+            headers,
+            Html(encode_text(&error).to_string()),
+        );
+    }
+    match get_admin_user(state, user_id).await {
+        Some(user) => (
+            StatusCode::OK,
+            headers,
+            templates::render(&templates::AdminUserRow { user }),
+        ),
+        None => (StatusCode::NOT_FOUND, headers, Html("".to_string())),
+    }
+}
+
+async fn revoke_all_sessions(state: &AppState, user_id: i32) {
+    sqlx::query("UPDATE auth_tokens SET revoked=true WHERE user_id=?")
+        .bind(user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke sessions");
+}
+
+pub async fn admin_activate_user(
+    State(state): State<AppState>,
+    Path(request): Path<AdminUserRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    sqlx::query("UPDATE users SET active=1 WHERE id=?")
+        .bind(request.user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to activate user");
+
+    admin_user_response(&state, request.user_id, None).await
+}
+
+pub async fn admin_deactivate_user(
+    State(state): State<AppState>,
+    admin: User,
+    Path(request): Path<AdminUserRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    if request.user_id == admin.id {
+        return admin_user_response(
+            &state,
+            request.user_id,
+            Some("You can't deactivate your own account".to_string()),
+        )
+        .await;
+    }
+
+    sqlx::query("UPDATE users SET active=0 WHERE id=?")
+        .bind(request.user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to deactivate user");
+    revoke_all_sessions(&state, request.user_id).await;
+    sqlx::query("UPDATE api_tokens SET revoked=true WHERE user_id=?")
+        .bind(request.user_id)
+        .execute(&state.connection_pool)
+        .await
+        .expect("Failed to revoke API tokens");
+
+    admin_user_response(&state, request.user_id, None).await
+}
+
+pub async fn admin_rename_user(
+    State(state): State<AppState>,
+    Path(request): Path<AdminUserRequest>,
+    Form(form_data): Form<AdminRenameRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    let username = form_data.username.trim();
+    if let Err(message) = validate_username(username) {
+        return admin_user_response(&state, request.user_id, Some(message)).await;
+    }
+
+    let result = sqlx::query("UPDATE users SET username=? WHERE id=?")
+        .bind(username)
+        .bind(request.user_id)
+        .execute(&state.connection_pool)
+        .await;
+    let error = match result {
+        Ok(_) => None,
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+            Some(format!("The username {} is already taken", username))
+        }
+        Err(e) => panic!("Failed to rename user: {}", e),
+    };
+
+    admin_user_response(&state, request.user_id, error).await
+}
+
+pub async fn admin_reset_password(
+    State(state): State<AppState>,
+    Path(request): Path<AdminUserRequest>,
+) -> Html<String> {
+    let Some(user) = get_admin_user(&state, request.user_id).await else {
+        return Html("That user doesn't exist".to_string());
+    };
+    if !user.active {
+        return Html("Activate the account before resetting its password".to_string());
+    }
+
+    send_emailed_link(
+        &state,
+        EmailedLink::PasswordReset,
+        user.id,
+        user.username.clone(),
+        user.email.clone(),
+        user.locale.clone(),
+    )
+    .await;
+
+    Html(format!(
+        "A password reset link has been sent to {}",
+        encode_text(&user.email)
+    ))
+}
+
+pub async fn admin_revoke_sessions(
+    State(state): State<AppState>,
+    Path(request): Path<AdminUserRequest>,
+) -> (StatusCode, HeaderMap, Html<String>) {
+    revoke_all_sessions(&state, request.user_id).await;
+
+    admin_user_response(&state, request.user_id, None).await
+}
 
 pub async fn login_status() -> StatusCode {
     StatusCode::NO_CONTENT
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{self, Authenticator, MockProvider, MOCK_EMAIL, MOCK_SUBJECT};
+    use axum_extra::extract::cookie::Cookie;
+
+    async fn add_user(state: &AppState, username: &str, email: &str) -> i32 {
+        sqlx::query(
+            "INSERT INTO users(email,username,hashed_password,active) values(?,?,'',1) RETURNING id",
+        )
+        .bind(email)
+        .bind(username)
+        .fetch_one(&state.connection_pool)
+        .await
+        .unwrap()
+        .try_get("id")
+        .unwrap()
+    }
+
+    // The cookies a browser would hold after getting these Set-Cookie headers
+    fn with_cookies(jar: CookieJar, headers: &HeaderMap) -> CookieJar {
+        headers
+            .get_all("Set-Cookie")
+            .iter()
+            .fold(jar, |jar, set_cookie| {
+                jar.add(Cookie::parse(set_cookie.to_str().unwrap().to_string()).unwrap())
+            })
+    }
+
+    async fn logged_in(state: &AppState, user_id: i32) -> CookieJar {
+        let set_cookie = start_session(state, user_id, &HeaderMap::new(), "127.0.0.1").await;
+        CookieJar::new().add(Cookie::parse(set_cookie).unwrap())
+    }
+
+    // What the provider sends the user back with
+    struct Login {
+        state: String,
+        code: String,
+    }
+
+    // Start a login, or a link when a user is given, then log in at the provider and have it issue a valid ID token.
+    // Returns what to call back with and the browser's cookies with the state cookie added.
+    async fn authorize(
+        state: &AppState,
+        jar: CookieJar,
+        linking_user: Option<i32>,
+        mock: &MockProvider,
+    ) -> (Login, CookieJar) {
+        let provider = Path(OidcProviderRequest {
+            provider: "mock".to_string(),
+        });
+        let (status, headers, _) = match linking_user {
+            Some(user_id) => {
+                let user = auth_and_login::get_user(user_id, state.connection_pool.clone())
+                    .await
+                    .unwrap();
+                start_oidc_link(State(state.clone()), user, provider).await
+            }
+            None => start_oidc_login(State(state.clone()), provider).await,
+        };
+        let location = match linking_user {
+            Some(_) => {
+                assert_eq!(status, StatusCode::OK);
+                &headers["HX-Redirect"]
+            }
+            None => {
+                assert_eq!(status, StatusCode::SEE_OTHER);
+                &headers["Location"]
+            }
+        };
+        let location = reqwest::Url::parse(location.to_str().unwrap()).unwrap();
+        let parameter = |name: &str| {
+            location
+                .query_pairs()
+                .find(|(key, _)| key == name)
+                .unwrap()
+                .1
+                .into_owned()
+        };
+        mock.issue(MockProvider::sign(
+            &mock.claims(&parameter("nonce")),
+            &MockProvider::key(),
+        ));
+        let login = Login {
+            state: parameter("state"),
+            code: mock.log_in(location.as_str()).await.unwrap(),
+        };
+        (login, with_cookies(jar, &headers))
+    }
+
+    async fn callback(state: &AppState, jar: CookieJar, login: &Login) -> (StatusCode, HeaderMap) {
+        let (status, headers, _) = finish_oidc_login(
+            State(state.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            HeaderMap::new(),
+            jar,
+            Path(OidcProviderRequest {
+                provider: "mock".to_string(),
+            }),
+            Query(OidcCallback {
+                code: Some(login.code.clone()),
+                state: Some(login.state.clone()),
+                error: None,
+            }),
+        )
+        .await;
+        (status, headers)
+    }
+
+    async fn linked_user(state: &AppState) -> Option<i32> {
+        sqlx::query("SELECT user_id FROM external_identities WHERE provider='mock' AND subject=?")
+            .bind(MOCK_SUBJECT)
+            .fetch_optional(&state.connection_pool)
+            .await
+            .unwrap()
+            .map(|row| row.try_get("user_id").unwrap())
+    }
+
+    fn has_session_cookie(headers: &HeaderMap) -> bool {
+        headers
+            .get_all("Set-Cookie")
+            .iter()
+            .any(|cookie| cookie.to_str().unwrap().starts_with("auth_token="))
+    }
+
+    #[tokio::test]
+    async fn oidc_login_cannot_be_replayed() {
+        let mock = MockProvider::start().await;
+        let state = test_support::test_state(vec![mock.provider()]).await;
+        let user_id = add_user(&state, "alice", MOCK_EMAIL).await;
+
+        let (login, jar) = authorize(&state, CookieJar::new(), None, &mock).await;
+        let (status, headers) = callback(&state, jar.clone(), &login).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(headers["Location"], "/home.html");
+        assert!(has_session_cookie(&headers));
+        assert_eq!(linked_user(&state).await, Some(user_id));
+
+        let (status, headers) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!has_session_cookie(&headers));
+    }
+
+    #[tokio::test]
+    async fn oidc_login_rejects_an_unknown_state() {
+        let mock = MockProvider::start().await;
+        let state = test_support::test_state(vec![mock.provider()]).await;
+        add_user(&state, "alice", MOCK_EMAIL).await;
+
+        let (login, jar) = authorize(&state, CookieJar::new(), None, &mock).await;
+        let jar = jar.add(Cookie::new("oidc_state", "made-up-state"));
+        let made_up = Login {
+            state: "made-up-state".to_string(),
+            code: login.code,
+        };
+        let (status, _) = callback(&state, jar, &made_up).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn oidc_login_must_finish_in_the_browser_that_started_it() {
+        let mock = MockProvider::start().await;
+        let state = test_support::test_state(vec![mock.provider()]).await;
+        add_user(&state, "alice", MOCK_EMAIL).await;
+
+        let (other_login, _) = authorize(&state, CookieJar::new(), None, &mock).await;
+        let (login, jar) = authorize(&state, CookieJar::new(), None, &mock).await;
+
+        // Someone else's authorize URL, followed by a browser without their state cookie
+        let (status, headers) = callback(&state, CookieJar::new(), &login).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(!has_session_cookie(&headers));
+
+        // A state cookie from a different login
+        let (status, _) = callback(&state, jar.clone(), &other_login).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Refusing those didn't use up the login for the browser that started it
+        let (status, headers) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert!(has_session_cookie(&headers));
+    }
+
+    #[tokio::test]
+    async fn logged_in_user_links_an_external_account() {
+        let mock = MockProvider::start().await;
+        let state = test_support::test_state(vec![mock.provider()]).await;
+        // Nothing ties the external account to alice except her starting the link
+        let alice = add_user(&state, "alice", "alice@other.example.com").await;
+        let bob = add_user(&state, "bob", "bob@other.example.com").await;
+
+        let jar = logged_in(&state, alice).await;
+        let (login, jar) = authorize(&state, jar, Some(alice), &mock).await;
+        let (status, headers) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::SEE_OTHER);
+        assert_eq!(headers["Location"], "/home.html");
+        assert_eq!(linked_user(&state).await, Some(alice));
+
+        let jar = logged_in(&state, bob).await;
+        let (login, jar) = authorize(&state, jar, Some(bob), &mock).await;
+        let (status, _) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(linked_user(&state).await, Some(alice));
+    }
+
+    #[tokio::test]
+    async fn oidc_link_must_finish_in_the_session_that_started_it() {
+        let mock = MockProvider::start().await;
+        let state = test_support::test_state(vec![mock.provider()]).await;
+        let alice = add_user(&state, "alice", "alice@other.example.com").await;
+        let bob = add_user(&state, "bob", "bob@other.example.com").await;
+
+        // Without a session
+        let (login, jar) = authorize(&state, CookieJar::new(), Some(alice), &mock).await;
+        let (status, _) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(linked_user(&state).await, None);
+
+        // In someone else's session
+        let jar = logged_in(&state, bob).await;
+        let (login, jar) = authorize(&state, jar, Some(alice), &mock).await;
+        let (status, _) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(linked_user(&state).await, None);
+    }
+
+    #[tokio::test]
+    async fn oidc_login_redeems_the_code_with_its_own_verifier() {
+        let mock = MockProvider::start().await;
+        let state = test_support::test_state(vec![mock.provider()]).await;
+        add_user(&state, "alice", MOCK_EMAIL).await;
+
+        let (login, jar) = authorize(&state, CookieJar::new(), None, &mock).await;
+        sqlx::query("UPDATE oidc_logins SET code_verifier='not-the-verifier' WHERE state=?")
+            .bind(&login.state)
+            .execute(&state.connection_pool)
+            .await
+            .unwrap();
+        let (status, headers) = callback(&state, jar, &login).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(!has_session_cookie(&headers));
+    }
+
+    // Register the authenticator's passkey to the user, then log in with it
+    async fn passkey_login(state: &AppState, user_id: i32) -> (StatusCode, HeaderMap) {
+        let authenticator = Authenticator::new();
+        sqlx::query(
+            "INSERT INTO webauthn_credentials (user_id,credential_id,public_key,sign_count,name,created) values(?,?,?,0,'Test',0)",
+        )
+        .bind(user_id)
+        .bind(webauthn::encode(&authenticator.credential_id))
+        .bind(authenticator.cose_key())
+        .execute(&state.connection_pool)
+        .await
+        .unwrap();
+
+        let options = start_passkey_login(State(state.clone())).await.0;
+        let (client_data, authenticator_data, signature) = authenticator.login(
+            &state.app_config.webauthn_rp_id,
+            &state.app_config.base_url,
+            options["challenge"].as_str().unwrap(),
+            webauthn::USER_PRESENT,
+            1,
+        );
+        let (status, headers, _) = finish_passkey_login(
+            State(state.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            HeaderMap::new(),
+            Json(PasskeyLogin {
+                credential_id: webauthn::encode(&authenticator.credential_id),
+                client_data_json: webauthn::encode(&client_data),
+                authenticator_data: webauthn::encode(&authenticator_data),
+                signature: webauthn::encode(&signature),
+            }),
+        )
+        .await;
+        (status, headers)
+    }
+
+    #[tokio::test]
+    async fn passkey_login_starts_a_session() {
+        let state = test_support::test_state(vec![]).await;
+        let user_id = add_user(&state, "alice", "alice@example.com").await;
+
+        let (status, headers) = passkey_login(&state, user_id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(has_session_cookie(&headers));
+        assert_eq!(headers["HX-Location"], "./home.html");
+    }
+
+    #[tokio::test]
+    async fn passkey_login_still_asks_for_the_totp_code() {
+        let state = test_support::test_state(vec![]).await;
+        let user_id = add_user(&state, "alice", "alice@example.com").await;
+        sqlx::query("UPDATE users SET totp_secret='GEZDGNBVGY3TQOJQ', totp_enabled=1 WHERE id=?")
+            .bind(user_id)
+            .execute(&state.connection_pool)
+            .await
+            .unwrap();
+
+        let (status, headers) = passkey_login(&state, user_id).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!has_session_cookie(&headers));
+        assert!(headers["Set-Cookie"]
+            .to_str()
+            .unwrap()
+            .starts_with("pending_login="));
+        assert_eq!(headers["HX-Location"], "./verify-login.html");
+    }
+
+    async fn register_as(state: &AppState, username: &str, email: &str) -> String {
+        let (_, Html(message)) = register(
+            State(state.clone()),
+            HeaderMap::new(),
+            Form(RegistrationRequest {
+                email: email.to_string(),
+                username: username.to_string(),
+                password: "password1".to_string(),
+                confirm_password: "password1".to_string(),
+            }),
+        )
+        .await;
+        message
+    }
+
+    async fn verify(state: &AppState, token: &str) -> StatusCode {
+        verify_email(
+            State(state.clone()),
+            Query(VerifyEmailRequest {
+                token: token.to_string(),
+            }),
+        )
+        .await
+        .0
+    }
+
+    async fn is_active(state: &AppState, username: &str) -> bool {
+        sqlx::query("SELECT active FROM users WHERE username=?")
+            .bind(username)
+            .fetch_one(&state.connection_pool)
+            .await
+            .unwrap()
+            .try_get("active")
+            .unwrap()
+    }
+
+    async fn expire_verifications(state: &AppState) {
+        sqlx::query("UPDATE email_verifications SET expiry=?")
+            .bind(utilities::get_epoch_time() - 1)
+            .execute(&state.connection_pool)
+            .await
+            .unwrap();
+    }
+
+    const REGISTERED: &str =
+        "Thanks for registering, please check your email for a verification link";
+    const TAKEN: &str = "That username or email address is already registered";
+
+    #[tokio::test]
+    async fn registration_is_verified_by_the_emailed_link() {
+        let (state, outbox) = test_support::test_state_with_outbox(vec![]).await;
+
+        assert_eq!(
+            register_as(&state, "alice", "alice@example.com").await,
+            REGISTERED
+        );
+        assert!(!is_active(&state, "alice").await);
+
+        let sent = test_support::next_email(&outbox).await;
+        assert_eq!(sent.to, "alice@example.com");
+        let token = test_support::emailed_token(&sent);
+
+        assert_eq!(verify(&state, &token).await, StatusCode::TEMPORARY_REDIRECT);
+        assert!(is_active(&state, "alice").await);
+
+        // The link only works once
+        assert_eq!(verify(&state, &token).await, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            verify(&state, "made-up-token").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_verification_links_are_rejected() {
+        let (state, outbox) = test_support::test_state_with_outbox(vec![]).await;
+        register_as(&state, "alice", "alice@example.com").await;
+        let token = test_support::emailed_token(&test_support::next_email(&outbox).await);
+
+        expire_verifications(&state).await;
+        assert_eq!(verify(&state, &token).await, StatusCode::BAD_REQUEST);
+        assert!(!is_active(&state, "alice").await);
+    }
+
+    #[tokio::test]
+    async fn usernames_and_emails_can_only_be_registered_once() {
+        let state = test_support::test_state(vec![]).await;
+        register_as(&state, "alice", "alice@example.com").await;
+
+        assert_eq!(
+            register_as(&state, "ALICE", "someone@example.com").await,
+            TAKEN
+        );
+        assert_eq!(register_as(&state, "bob", "Alice@Example.com").await, TAKEN);
+        assert_eq!(
+            register_as(&state, "bob", "bob@example.com").await,
+            REGISTERED
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_registrations_give_up_their_username_and_email() {
+        let (state, outbox) = test_support::test_state_with_outbox(vec![]).await;
+        register_as(&state, "alice", "alice@example.com").await;
+        let expired_token = test_support::emailed_token(&test_support::next_email(&outbox).await);
+        expire_verifications(&state).await;
+
+        assert_eq!(
+            register_as(&state, "alice", "alice@example.com").await,
+            REGISTERED
+        );
+        let token = test_support::emailed_token(&test_support::next_email(&outbox).await);
+        assert_eq!(
+            verify(&state, &expired_token).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(verify(&state, &token).await, StatusCode::TEMPORARY_REDIRECT);
+        assert!(is_active(&state, "alice").await);
+
+        // Verified accounts keep their name however old the link was
+        expire_verifications(&state).await;
+        assert_eq!(
+            register_as(&state, "alice", "other@example.com").await,
+            TAKEN
+        );
+    }
+
+    async fn change_password(
+        state: &AppState,
+        user_id: i32,
+        jar: &CookieJar,
+        current_password: &str,
+        password: &str,
+    ) -> (StatusCode, String) {
+        let user = auth_and_login::get_user(user_id, state.connection_pool.clone())
+            .await
+            .unwrap();
+        let (status, Html(message)) = update_password(
+            State(state.clone()),
+            user,
+            jar.clone(),
+            Form(UpdatePasswordRequest {
+                current_password: current_password.to_string(),
+                password: password.to_string(),
+                confirm_password: password.to_string(),
+            }),
+        )
+        .await;
+        (status, message)
+    }
+
+    async fn is_revoked(state: &AppState, jar: &CookieJar) -> bool {
+        sqlx::query("SELECT revoked FROM auth_tokens WHERE token_hash=?")
+            .bind(auth_and_login::hash_token(
+                &auth_and_login::get_auth_token(jar),
+                &state.app_config.token_secret,
+            ))
+            .fetch_one(&state.connection_pool)
+            .await
+            .unwrap()
+            .try_get("revoked")
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn changing_password_needs_the_current_one_and_a_valid_new_one() {
+        let mut state = test_support::test_state(vec![]).await;
+        state.app_config.password_policy.require_number = true;
+        let user_id = add_user(&state, "alice", "alice@example.com").await;
+        sqlx::query("UPDATE users SET hashed_password=? WHERE id=?")
+            .bind(auth_and_login::hash_password("password1".to_string()))
+            .bind(user_id)
+            .execute(&state.connection_pool)
+            .await
+            .unwrap();
+        let jar = logged_in(&state, user_id).await;
+        let other_session = logged_in(&state, user_id).await;
+
+        assert_eq!(
+            change_password(&state, user_id, &jar, "password2", "new password 1").await,
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Your current password is incorrect".to_string()
+            )
+        );
+        assert_eq!(
+            change_password(&state, user_id, &jar, "password1", "short1").await,
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Your password must be at least 8 characters long".to_string()
+            )
+        );
+        let (status, _) =
+            change_password(&state, user_id, &jar, "password1", "no numbers here").await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        // None of that changed anything
+        assert!(
+            auth_and_login::verify_password(user_id, "password1", state.connection_pool.clone())
+                .await
+        );
+        assert!(!is_revoked(&state, &other_session).await);
+
+        assert_eq!(
+            change_password(&state, user_id, &jar, "password1", "new password 1").await,
+            (StatusCode::OK, "Your password has been updated".to_string())
+        );
+        assert!(
+            auth_and_login::verify_password(
+                user_id,
+                "new password 1",
+                state.connection_pool.clone()
+            )
+            .await
+        );
+        assert!(
+            !auth_and_login::verify_password(user_id, "password1", state.connection_pool.clone())
+                .await
+        );
+
+        // Every other session is signed out, but not the one that made the change
+        assert!(is_revoked(&state, &other_session).await);
+        assert!(!is_revoked(&state, &jar).await);
+    }
+
+    async fn add(state: &AppState, user_id: i32, name: &str, url: &str) -> (HeaderMap, String) {
+        let user = auth_and_login::get_user(user_id, state.connection_pool.clone())
+            .await
+            .unwrap();
+        let (headers, Html(html)) = add_item(
+            State(state.clone()),
+            user,
+            Form(Item {
+                name: name.to_string(),
+                url: url.to_string(),
+                price: 10.0,
+            }),
+        )
+        .await;
+        (headers, html)
+    }
+
+    #[tokio::test]
+    async fn items_need_a_web_url() {
+        let state = test_support::test_state(vec![]).await;
+        let user_id = add_user(&state, "alice", "alice@example.com").await;
+
+        for url in [
+            "javascript:alert(1)",
+            " JavaScript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+        ] {
+            let (headers, html) = add(&state, user_id, "Socks", url).await;
+            assert_eq!(headers["HX-Retarget"], "#add-response");
+            assert_eq!(headers["HX-Reswap"], "innerHTML");
+            assert_eq!(html, "The URL must start with http:// or https://");
+        }
+        let items: i64 = sqlx::query("SELECT count(*) AS count FROM presents")
+            .fetch_one(&state.connection_pool)
+            .await
+            .unwrap()
+            .get("count");
+        assert_eq!(items, 0);
+    }
+
+    #[tokio::test]
+    async fn added_items_come_back_escaped() {
+        let state = test_support::test_state(vec![]).await;
+        let user_id = add_user(&state, "alice", "alice@example.com").await;
+
+        let (headers, html) = add(
+            &state,
+            user_id,
+            "<script>alert(1)</script>",
+            "https://example.com/?q='><script>",
+        )
+        .await;
+        assert!(headers.get("HX-Retarget").is_none());
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(
+            html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{}",
+            html
+        );
+
+        // Stored as entered, since escaping happens when it's shown
+        let name: String = sqlx::query("SELECT name FROM presents WHERE user_id=?")
+            .bind(user_id)
+            .fetch_one(&state.connection_pool)
+            .await
+            .unwrap()
+            .get("name");
+        assert_eq!(name, "<script>alert(1)</script>");
+    }
+
+    async fn request_login_link(state: &AppState, email: &str) -> String {
+        let (_, Html(message)) = request_magic_link(
+            State(state.clone()),
+            Form(MagicLinkRequest {
+                email: email.to_string(),
+            }),
+        )
+        .await;
+        message
+    }
+
+    async fn use_login_link(state: &AppState, token: &str) -> bool {
+        let (headers, _) = process_magic_link(
+            State(state.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            HeaderMap::new(),
+            Form(MagicLinkLoginRequest {
+                token: token.to_string(),
+            }),
+        )
+        .await;
+        has_session_cookie(&headers)
+    }
+
+    #[tokio::test]
+    async fn only_the_latest_emailed_link_works() {
+        let (state, outbox) = test_support::test_state_with_outbox(vec![]).await;
+        add_user(&state, "alice", "alice@example.com").await;
+
+        let message = request_login_link(&state, "Alice@Example.com").await;
+        let first = test_support::emailed_token(&test_support::next_email(&outbox).await);
+
+        // Asking again straight away doesn't send another
+        request_login_link(&state, "alice@example.com").await;
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(outbox.sent.lock().unwrap().is_empty());
+
+        sqlx::query("UPDATE login_links SET created=created-60")
+            .execute(&state.connection_pool)
+            .await
+            .unwrap();
+        request_login_link(&state, "alice@example.com").await;
+        let second = test_support::emailed_token(&test_support::next_email(&outbox).await);
+
+        assert!(!use_login_link(&state, &first).await);
+        assert!(use_login_link(&state, &second).await);
+        assert!(!use_login_link(&state, &second).await);
+
+        // Unknown addresses get the same response and no email
+        assert_eq!(request_login_link(&state, "bob@example.com").await, message);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(outbox.sent.lock().unwrap().is_empty());
+    }
+}
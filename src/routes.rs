@@ -1,21 +1,134 @@
-use crate::{route_handlers, AppState};
+use crate::config::FeatureConfig;
+use crate::{auth_and_login, route_handlers, AppState};
+use axum::middleware;
 use axum::routing::{delete, get, patch, post};
 use axum::Router;
 
-pub fn get_protected_routes() -> Router<AppState> {
+pub fn get_protected_routes(features: &FeatureConfig) -> Router<AppState> {
     Router::new()
         .route("/item", post(route_handlers::add_item))
         .route("/item/:item_id", delete(route_handlers::delete_item))
         .route("/item/:item_id", patch(route_handlers::allocate_item))
         .route("/items/:user_id", get(route_handlers::get_items))
         .route("/items/", get(route_handlers::get_items))
-        .route("/password", patch(route_handlers::update_password))
         .route("/users", get(route_handlers::get_users))
-        .route("/logout", get(route_handlers::logout))
         .route("/loginStatus", get(route_handlers::login_status))
+        .merge(
+            get_account_routes(features)
+                .route_layer(middleware::from_fn(auth_and_login::require_session)),
+        )
+}
+
+// Routes which can't be used with a personal API token
+fn get_account_routes(features: &FeatureConfig) -> Router<AppState> {
+    let mut router = Router::new()
+        .route("/accountLinks", get(route_handlers::get_account_links))
+        .route("/password", patch(route_handlers::update_password))
+        .route("/sessions", get(route_handlers::get_sessions))
+        .route(
+            "/sessions/:session_id",
+            delete(route_handlers::revoke_session),
+        )
+        .route("/totp", get(route_handlers::get_totp_status))
+        .route("/totp/setup", post(route_handlers::setup_totp))
+        .route("/totp/enable", post(route_handlers::enable_totp))
+        .route("/totp/disable", post(route_handlers::disable_totp))
+        .route("/logout", post(route_handlers::logout))
+        .route("/logoutEverywhere", post(route_handlers::logout_everywhere))
+        .route(
+            "/oidc/:provider/link",
+            post(route_handlers::start_oidc_link),
+        );
+
+    if features.api_tokens {
+        router = router
+            .route("/apiTokens", get(route_handlers::get_api_tokens))
+            .route("/apiTokens", post(route_handlers::create_api_token))
+            .route(
+                "/apiTokens/:token_id",
+                delete(route_handlers::revoke_api_token),
+            );
+    }
+    if features.passkeys {
+        router = router
+            .route(
+                "/passkey/register/start",
+                post(route_handlers::start_passkey_registration),
+            )
+            .route(
+                "/passkey/register/finish",
+                post(route_handlers::finish_passkey_registration),
+            )
+            .route("/passkeys", get(route_handlers::get_passkeys))
+            .route(
+                "/passkeys/:passkey_id",
+                delete(route_handlers::delete_passkey),
+            );
+    }
+    router
 }
 
-pub fn get_open_routes() -> Router<AppState> {
-    Router::new().route("/login", post(route_handlers::process_login))
-    // .route("/register", post(route_handlers::register))
+// Routes for the admin console, only available to admins
+pub fn get_admin_routes() -> Router<AppState> {
+    Router::new()
+        .route("/admin/users", get(route_handlers::admin_get_users))
+        .route(
+            "/admin/users/:user_id/activate",
+            patch(route_handlers::admin_activate_user),
+        )
+        .route(
+            "/admin/users/:user_id/deactivate",
+            patch(route_handlers::admin_deactivate_user),
+        )
+        .route(
+            "/admin/users/:user_id/username",
+            patch(route_handlers::admin_rename_user),
+        )
+        .route(
+            "/admin/users/:user_id/resetPassword",
+            post(route_handlers::admin_reset_password),
+        )
+        .route(
+            "/admin/users/:user_id/sessions",
+            delete(route_handlers::admin_revoke_sessions),
+        )
+}
+
+pub fn get_open_routes(features: &FeatureConfig) -> Router<AppState> {
+    let mut router = Router::new()
+        .route("/login", post(route_handlers::process_login))
+        .route("/login/totp", post(route_handlers::process_totp_login))
+        .route("/loginOptions", get(route_handlers::get_login_options))
+        .route(
+            "/oidc/:provider/login",
+            get(route_handlers::start_oidc_login),
+        )
+        .route(
+            "/oidc/:provider/callback",
+            get(route_handlers::finish_oidc_login),
+        )
+        .route("/verify", get(route_handlers::verify_email))
+        .route("/forgotPassword", post(route_handlers::forgot_password))
+        .route("/resetPassword", post(route_handlers::reset_password));
+
+    if features.registration {
+        router = router.route("/register", post(route_handlers::register));
+    }
+    if features.magic_links {
+        router = router
+            .route("/magicLink", post(route_handlers::request_magic_link))
+            .route("/magicLink/login", post(route_handlers::process_magic_link));
+    }
+    if features.passkeys {
+        router = router
+            .route(
+                "/passkey/login/start",
+                post(route_handlers::start_passkey_login),
+            )
+            .route(
+                "/passkey/login/finish",
+                post(route_handlers::finish_passkey_login),
+            );
+    }
+    router
 }
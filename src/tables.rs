@@ -1,18 +1,196 @@
-use sqlx::SqlitePool;
+use crate::utilities;
+use sqlx::{Row, SqliteConnection, SqlitePool};
 
-// Create tables
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+}
+
+// Every change to the schema gets a new migration at the end. Never change one that has been released.
+pub const MIGRATIONS: [Migration; 6] = [
+    Migration {
+        version: 1,
+        description: "Create the schema as it was before versioned migrations",
+    },
+    Migration {
+        version: 2,
+        description: "Add foreign keys from presents to users",
+    },
+    Migration {
+        version: 3,
+        description: "Add a preferred locale for emails to users",
+    },
+    Migration {
+        version: 4,
+        description: "Store hashes of password reset and email verification tokens",
+    },
+    Migration {
+        version: 5,
+        description: "Record when password resets were requested",
+    },
+    Migration {
+        version: 6,
+        description: "Record who started an OIDC login to link accounts",
+    },
+];
+
+// Bring the database schema up to date, applying each missing migration in its own transaction
 pub async fn create(pool: SqlitePool) {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            description VARCHAR(200),
+            applied INTEGER)",
+    )
+    .execute(&pool)
+    .await
+    .expect("Failed to create table");
+
+    let current_version = current_version(&pool).await;
+    let latest_version = MIGRATIONS.last().map(|m| m.version).unwrap_or(0);
+    if current_version > latest_version {
+        panic!(
+            "The database schema is version {} but this build only knows up to version {}",
+            current_version, latest_version
+        );
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current_version) {
+        let mut transaction = pool.begin().await.expect("Failed to start transaction");
+        apply(migration.version, &mut transaction)
+            .await
+            .unwrap_or_else(|e| panic!("Migration {} failed: {}", migration.version, e));
+        sqlx::query("INSERT INTO schema_version (version,description,applied) values(?,?,?)")
+            .bind(migration.version)
+            .bind(migration.description)
+            .bind(utilities::get_epoch_time())
+            .execute(&mut *transaction)
+            .await
+            .expect("Failed to record migration");
+        transaction
+            .commit()
+            .await
+            .expect("Failed to commit migration");
+        println!(
+            "Applied migration {}: {}",
+            migration.version, migration.description
+        );
+    }
+}
+
+// The version of the latest migration applied, or 0 for a new database
+pub async fn current_version(pool: &SqlitePool) -> i64 {
+    sqlx::query("SELECT coalesce(max(version), 0) AS version FROM schema_version")
+        .fetch_one(pool)
+        .await
+        .map(|row| row.get("version"))
+        .unwrap_or(0)
+}
+
+// When each migration was applied, None if it hasn't been yet
+pub async fn status(pool: &SqlitePool) -> Vec<(&'static Migration, Option<i64>)> {
+    let applied: Vec<(i64, i64)> = sqlx::query("SELECT version,applied FROM schema_version")
+        .fetch_all(pool)
+        .await
+        .unwrap_or_default()
+        .iter()
+        .map(|row| (row.get("version"), row.get("applied")))
+        .collect();
+
+    MIGRATIONS
+        .iter()
+        .map(|migration| {
+            let applied = applied
+                .iter()
+                .find(|(version, _applied)| *version == migration.version)
+                .map(|(_version, applied)| *applied);
+            (migration, applied)
+        })
+        .collect()
+}
+
+async fn apply(version: i64, conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
+    match version {
+        1 => create_unversioned_schema(conn).await,
+        2 => {
+            // SQLite can't add a foreign key to an existing table, so presents is rebuilt
+            for statement in [
+                "DELETE FROM presents WHERE user_id IS NULL OR user_id NOT IN (SELECT id FROM users)",
+                "UPDATE presents SET taken=false, taken_by_id=NULL WHERE taken_by_id IS NOT NULL AND taken_by_id NOT IN (SELECT id FROM users)",
+                "CREATE TABLE presents_new (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+                    name VARCHAR(100),
+                    url VARCHAR(500),
+                    price VARCHAR(30),
+                    taken BOOLEAN,
+                    taken_by_id INTEGER REFERENCES users(id) ON DELETE SET NULL)",
+                "INSERT INTO presents_new (id,user_id,name,url,price,taken,taken_by_id)
+                    SELECT id,user_id,name,url,price,taken,taken_by_id FROM presents",
+                "DROP TABLE presents",
+                "ALTER TABLE presents_new RENAME TO presents",
+                "CREATE INDEX presents_user_id ON presents (user_id)",
+                "CREATE INDEX presents_taken_by_id ON presents (taken_by_id)",
+            ] {
+                sqlx::query(statement).execute(&mut *conn).await?;
+            }
+            Ok(())
+        }
+        3 => {
+            sqlx::query("ALTER TABLE users ADD COLUMN locale VARCHAR(10) NOT NULL DEFAULT 'en'")
+                .execute(&mut *conn)
+                .await?;
+            Ok(())
+        }
+        4 => {
+            for statement in [
+                "ALTER TABLE password_resets ADD COLUMN token_hash VARCHAR(64)",
+                "ALTER TABLE email_verifications ADD COLUMN token_hash VARCHAR(64)",
+                // The plaintext tokens can't be hashed without the secret, so outstanding links stop working
+                "UPDATE password_resets SET used=true, token=NULL",
+                // Expiring the links lets anyone who hadn't verified yet register again
+                "UPDATE email_verifications SET expiry=0 WHERE used=false",
+                "UPDATE email_verifications SET token=NULL",
+                "CREATE UNIQUE INDEX password_resets_token_hash ON password_resets (token_hash)",
+                "CREATE UNIQUE INDEX email_verifications_token_hash ON email_verifications (token_hash)",
+            ] {
+                sqlx::query(statement).execute(&mut *conn).await?;
+            }
+            Ok(())
+        }
+        5 => {
+            sqlx::query("ALTER TABLE password_resets ADD COLUMN created INTEGER")
+                .execute(&mut *conn)
+                .await?;
+            Ok(())
+        }
+        6 => {
+            sqlx::query("ALTER TABLE oidc_logins ADD COLUMN user_id INTEGER")
+                .execute(&mut *conn)
+                .await?;
+            Ok(())
+        }
+        _ => unreachable!("No migration for version {}", version),
+    }
+}
+
+// Databases from before versioned migrations could be at any point along the way, so every step checks first
+async fn create_unversioned_schema(conn: &mut SqliteConnection) -> Result<(), sqlx::Error> {
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS auth_tokens (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
             token VARCHAR(30),
+            token_hash VARCHAR(64),
             user_id INTEGER,
             expiry INTEGER,
-            revoked BOOLEAN)",
+            revoked BOOLEAN,
+            user_agent VARCHAR(300),
+            ip_address VARCHAR(50),
+            created INTEGER,
+            last_seen INTEGER)",
     )
-    .execute(&pool)
-    .await
-    .expect("Failed to create table");
+    .execute(&mut *conn)
+    .await?;
 
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS users (
@@ -20,12 +198,15 @@ pub async fn create(pool: SqlitePool) {
             username VARCHAR(30) UNIQUE,
             email VARCHAR(100) UNIQUE,
             hashed_password VARCHAR(200),
-            active INTEGER DEFAULT 0)
+            active INTEGER DEFAULT 0,
+            totp_secret VARCHAR(64),
+            totp_enabled INTEGER DEFAULT 0,
+            totp_last_step INTEGER,
+            role VARCHAR(10) DEFAULT 'user')
         ",
     )
-    .execute(&pool)
-    .await
-    .expect("Failed to create table");
+    .execute(&mut *conn)
+    .await?;
 
     sqlx::query(
         "CREATE TABLE IF NOT EXISTS presents(
@@ -39,7 +220,333 @@ pub async fn create(pool: SqlitePool) {
 )
         ",
     )
-    .execute(&pool)
-    .await
-    .expect("Failed to create table");
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS email_verifications (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token VARCHAR(30) UNIQUE,
+            user_id INTEGER,
+            expiry INTEGER,
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS password_resets (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token VARCHAR(30) UNIQUE,
+            user_id INTEGER,
+            expiry INTEGER,
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS recovery_codes (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            code_hash VARCHAR(64),
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS pending_logins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token_hash VARCHAR(64) UNIQUE,
+            user_id INTEGER,
+            expiry INTEGER,
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS webauthn_credentials (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            credential_id VARCHAR(400) UNIQUE,
+            public_key BLOB,
+            sign_count INTEGER,
+            name VARCHAR(100),
+            created INTEGER,
+            last_used INTEGER)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS webauthn_challenges (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            challenge VARCHAR(100) UNIQUE,
+            user_id INTEGER,
+            ceremony VARCHAR(20),
+            expiry INTEGER,
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS login_links (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token_hash VARCHAR(64) UNIQUE,
+            user_id INTEGER,
+            created INTEGER,
+            expiry INTEGER,
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS oidc_logins (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            state VARCHAR(100) UNIQUE,
+            provider VARCHAR(50),
+            nonce VARCHAR(100),
+            code_verifier VARCHAR(100),
+            expiry INTEGER,
+            used BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS external_identities (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            provider VARCHAR(50),
+            subject VARCHAR(255),
+            user_id INTEGER,
+            created INTEGER,
+            UNIQUE(provider, subject))",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    // Columns added after the table was first created
+    add_column_if_missing(conn, "auth_tokens", "user_agent", "VARCHAR(300)").await?;
+    add_column_if_missing(conn, "auth_tokens", "ip_address", "VARCHAR(50)").await?;
+    add_column_if_missing(conn, "auth_tokens", "created", "INTEGER").await?;
+    add_column_if_missing(conn, "auth_tokens", "last_seen", "INTEGER").await?;
+    add_column_if_missing(conn, "auth_tokens", "token_hash", "VARCHAR(64)").await?;
+    add_column_if_missing(conn, "users", "totp_secret", "VARCHAR(64)").await?;
+    add_column_if_missing(conn, "users", "totp_enabled", "INTEGER DEFAULT 0").await?;
+    add_column_if_missing(conn, "users", "totp_last_step", "INTEGER").await?;
+    add_column_if_missing(conn, "users", "role", "VARCHAR(10) DEFAULT 'user'").await?;
+
+    // Tokens used to be stored in plaintext, so revoke and wipe any that are left
+    sqlx::query("UPDATE auth_tokens SET revoked=true, token=NULL WHERE token IS NOT NULL")
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS api_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            name VARCHAR(100),
+            token_hash VARCHAR(100) UNIQUE,
+            scope VARCHAR(10),
+            created INTEGER,
+            last_used INTEGER,
+            revoked BOOLEAN)",
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query("CREATE INDEX IF NOT EXISTS auth_tokens_token_hash ON auth_tokens (token_hash)")
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// Add a column to an existing table unless it is already there
+async fn add_column_if_missing(
+    conn: &mut SqliteConnection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let columns: Vec<String> =
+        sqlx::query(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .fetch_all(&mut *conn)
+            .await?
+            .iter()
+            .map(|row| row.get("name"))
+            .collect();
+
+    if !columns.iter().any(|name| name == column) {
+        sqlx::query(&format!(
+            "ALTER TABLE {} ADD COLUMN {} {}",
+            table, column, definition
+        ))
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    // The tables as the site created them before versioned migrations
+    const BASELINE_SCHEMA: [&str; 3] = [
+        "CREATE TABLE auth_tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token VARCHAR(30),
+            user_id INTEGER,
+            expiry INTEGER,
+            revoked BOOLEAN)",
+        "CREATE TABLE users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username VARCHAR(30) UNIQUE,
+            email VARCHAR(100) UNIQUE,
+            hashed_password VARCHAR(200),
+            active INTEGER DEFAULT 0)",
+        "CREATE TABLE presents(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER,
+            name VARCHAR(100),
+            url VARCHAR(500),
+            price VARCHAR (30),
+            taken BOOLEAN,
+            taken_by_id INTEGER)",
+    ];
+
+    // A database from before versioned migrations, with some presents left behind by deleted users
+    async fn baseline_pool() -> SqlitePool {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        for statement in BASELINE_SCHEMA.into_iter().chain([
+            "INSERT INTO users (id,username,email,hashed_password,active) values
+                (1,'alice','alice@example.com','hash',1),
+                (2,'bob','bob@example.com','hash',1)",
+            "INSERT INTO auth_tokens (token,user_id,expiry,revoked) values ('token',1,9999999999,false)",
+            "INSERT INTO presents (id,user_id,name,url,price,taken,taken_by_id) values
+                (1,1,'Socks','https://example.com/socks','10',true,2),
+                (2,2,'Book','https://example.com/book','20',false,NULL),
+                (3,99,'Orphaned','https://example.com','30',false,NULL),
+                (4,NULL,'No owner','https://example.com','40',false,NULL),
+                (5,1,'Scarf','https://example.com/scarf','50',true,42)",
+        ]) {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        pool
+    }
+
+    async fn presents(pool: &SqlitePool) -> Vec<(i32, i32, String, bool, Option<i32>)> {
+        sqlx::query("SELECT id,user_id,name,taken,taken_by_id FROM presents ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+            .iter()
+            .map(|row| {
+                (
+                    row.get("id"),
+                    row.get("user_id"),
+                    row.get("name"),
+                    row.get("taken"),
+                    row.get("taken_by_id"),
+                )
+            })
+            .collect()
+    }
+
+    // Everything that could change: the schema, the migrations recorded and the data this test cares about
+    async fn snapshot(pool: &SqlitePool) -> Vec<String> {
+        let queries = [
+            "SELECT type || ' ' || name || ': ' || coalesce(sql, '') AS line FROM sqlite_master ORDER BY type, name",
+            "SELECT version || ' ' || applied AS line FROM schema_version ORDER BY version",
+            "SELECT id || ' ' || username || ' ' || active || ' ' || locale AS line FROM users ORDER BY id",
+            "SELECT id || ' ' || user_id || ' ' || coalesce(taken_by_id, '') AS line FROM presents ORDER BY id",
+            "SELECT id || ' ' || user_id AS line FROM auth_tokens ORDER BY id",
+        ];
+        let mut lines = vec![];
+        for query in queries {
+            for row in sqlx::query(query).fetch_all(pool).await.unwrap() {
+                lines.push(row.get("line"));
+            }
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn upgrades_a_database_from_before_migrations() {
+        let pool = baseline_pool().await;
+        create(pool.clone()).await;
+
+        assert_eq!(current_version(&pool).await, MIGRATIONS.len() as i64);
+
+        // Presents of users who no longer exist are deleted, and ones bought by them are free again
+        assert_eq!(
+            presents(&pool).await,
+            [
+                (1, 1, "Socks".to_string(), true, Some(2)),
+                (2, 2, "Book".to_string(), false, None),
+                (5, 1, "Scarf".to_string(), false, None),
+            ]
+        );
+
+        let users: Vec<(String, bool, String)> =
+            sqlx::query("SELECT username,active,locale FROM users ORDER BY id")
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| (row.get("username"), row.get("active"), row.get("locale")))
+                .collect();
+        assert_eq!(
+            users,
+            [
+                ("alice".to_string(), true, "en".to_string()),
+                ("bob".to_string(), true, "en".to_string()),
+            ]
+        );
+        let sessions: i64 = sqlx::query("SELECT count(*) AS count FROM auth_tokens")
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("count");
+        assert_eq!(sessions, 1);
+
+        let foreign_keys: Vec<(String, String, String)> =
+            sqlx::query("PRAGMA foreign_key_list(presents)")
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|row| (row.get("from"), row.get("table"), row.get("on_delete")))
+                .collect();
+        assert_eq!(foreign_keys.len(), 2);
+        assert!(foreign_keys.contains(&(
+            "user_id".to_string(),
+            "users".to_string(),
+            "CASCADE".to_string()
+        )));
+        assert!(foreign_keys.contains(&(
+            "taken_by_id".to_string(),
+            "users".to_string(),
+            "SET NULL".to_string()
+        )));
+    }
+
+    #[tokio::test]
+    async fn running_again_changes_nothing() {
+        let pool = baseline_pool().await;
+        create(pool.clone()).await;
+        let migrated = snapshot(&pool).await;
+
+        create(pool.clone()).await;
+        assert_eq!(snapshot(&pool).await, migrated);
+    }
 }
@@ -0,0 +1,381 @@
+use crate::config::{FeatureConfig, OidcProvider};
+use crate::route_handlers::{AdminUser, ApiToken, Passkey, Present, Session};
+use askama::Template;
+use axum::response::Html;
+
+// HTML fragments returned to HTMX, compiled from the files in templates/ with everything escaped by default
+
+// Render a fragment as a response body
+pub fn render(template: &impl Template) -> Html<String> {
+    Html(template.render().expect("Failed to render template"))
+}
+
+mod filters {
+    use crate::auth_and_login::ApiScope;
+    use crate::utilities;
+
+    pub fn timestamp(time: &i64) -> askama::Result<String> {
+        Ok(utilities::format_timestamp(*time))
+    }
+
+    // Items added before URLs were checked could have any scheme, so those links go nowhere
+    pub fn web_url(url: &str) -> askama::Result<&str> {
+        Ok(if utilities::is_web_url(url) { url } else { "#" })
+    }
+
+    pub fn scope_description(scope: &str) -> askama::Result<&'static str> {
+        Ok(ApiScope::parse(scope)
+            .map(|scope| scope.description())
+            .unwrap_or("Unknown"))
+    }
+}
+
+#[derive(Template)]
+#[template(path = "login_totp.html")]
+pub struct LoginTotp;
+
+#[derive(Template)]
+#[template(path = "login_options.html")]
+pub struct LoginOptions<'a> {
+    pub features: &'a FeatureConfig,
+    pub providers: &'a [OidcProvider],
+}
+
+#[derive(Template)]
+#[template(path = "oidc_error.html")]
+pub struct OidcError<'a> {
+    pub message: &'a str,
+}
+
+#[derive(Template)]
+#[template(path = "items.html")]
+pub struct Items {
+    pub own_list: bool,
+    pub items: Vec<Present>,
+}
+
+// A row in the user's own list, as added
+#[derive(Template)]
+#[template(path = "item_row.html")]
+pub struct ItemRow {
+    pub item: Present,
+}
+
+// The cells of a row in someone else's list once the user is buying it
+#[derive(Template)]
+#[template(path = "allocated_item.html")]
+pub struct AllocatedItem {
+    pub item: Present,
+}
+
+#[derive(Template)]
+#[template(path = "users_select.html")]
+pub struct UsersSelect {
+    pub own_id: i32,
+    pub users: Vec<(i32, String)>,
+}
+
+#[derive(Template)]
+#[template(path = "account_links.html")]
+pub struct AccountLinks<'a> {
+    pub features: &'a FeatureConfig,
+    pub providers: &'a [OidcProvider],
+    pub admin: bool,
+}
+
+#[derive(Template)]
+#[template(path = "sessions.html")]
+pub struct Sessions {
+    pub sessions: Vec<Session>,
+}
+
+#[derive(Template)]
+#[template(path = "api_tokens.html")]
+pub struct ApiTokens {
+    pub tokens: Vec<ApiToken>,
+}
+
+#[derive(Template)]
+#[template(path = "api_token_created.html")]
+pub struct ApiTokenCreated {
+    pub token: String,
+}
+
+#[derive(Template)]
+#[template(path = "totp_status.html")]
+pub struct TotpStatus {
+    pub enabled: bool,
+}
+
+#[derive(Template)]
+#[template(path = "totp_setup.html")]
+pub struct TotpSetup {
+    pub secret: String,
+    // Generated by the qrcode crate rather than from anything the user entered
+    pub qr_code_svg: String,
+}
+
+#[derive(Template)]
+#[template(path = "recovery_codes.html")]
+pub struct RecoveryCodes {
+    pub codes: Vec<String>,
+}
+
+#[derive(Template)]
+#[template(path = "passkeys.html")]
+pub struct Passkeys {
+    pub passkeys: Vec<Passkey>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_users.html")]
+pub struct AdminUsers {
+    pub users: Vec<AdminUser>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_user_row.html")]
+pub struct AdminUserRow {
+    pub user: AdminUser,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Breaking out of a quoted attribute into a new element
+    const SCRIPT: &str = "x'><script>alert(1)</script>";
+    // Staying inside the tag, such as the quoted hx-confirm text, and adding a handler
+    const HANDLER: &str = "x' onmouseover='alert(1)";
+    const URLS: [&str; 3] = [
+        "javascript:alert(1)",
+        "JavaScript:alert(1)",
+        "data:text/html,<script>alert(1)</script>",
+    ];
+
+    fn present(name: &str, url: &str) -> Present {
+        Present {
+            id: 1,
+            name: name.to_string(),
+            url: url.to_string(),
+            price: "10.00".to_string(),
+            taken: true,
+            taken_by_name: Some(name.to_string()),
+        }
+    }
+
+    // Every item or user in these tests is named with one of the payloads, so both must come out escaped
+    fn assert_escaped(html: &str) {
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(!html.contains("' onmouseover"), "{}", html);
+        assert!(
+            html.contains("x&#x27;&gt;&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("x&#x27; onmouseover=&#x27;alert(1)"),
+            "{}",
+            html
+        );
+    }
+
+    fn assert_no_script_urls(html: &str) {
+        assert!(!html.to_lowercase().contains("javascript:"), "{}", html);
+        assert!(!html.contains("data:"), "{}", html);
+    }
+
+    fn render_all<T: Template>(templates: impl IntoIterator<Item = T>) -> String {
+        templates.into_iter().map(|t| render(&t).0).collect()
+    }
+
+    #[test]
+    fn item_row_escapes_names_and_drops_script_urls() {
+        let html = render_all([SCRIPT, HANDLER].map(|name| ItemRow {
+            item: present(name, "https://example.com"),
+        }));
+        assert_escaped(&html);
+
+        for url in URLS {
+            let html = render(&ItemRow {
+                item: present("Socks", url),
+            })
+            .0;
+            assert_no_script_urls(&html);
+            assert!(html.contains("<a href='#'>Socks</a>"), "{}", html);
+        }
+    }
+
+    #[test]
+    fn own_items_escape_names_and_drop_script_urls() {
+        let mut items = vec![
+            present(SCRIPT, "https://example.com"),
+            present(HANDLER, "https://example.com"),
+        ];
+        items.extend(URLS.map(|url| present("Socks", url)));
+        let html = render(&Items {
+            own_list: true,
+            items,
+        })
+        .0;
+        assert_escaped(&html);
+        assert_no_script_urls(&html);
+    }
+
+    #[test]
+    fn other_items_escape_names_and_drop_script_urls() {
+        let mut items = vec![
+            present(SCRIPT, "https://example.com"),
+            present(HANDLER, "https://example.com"),
+        ];
+        items.extend(URLS.map(|url| present("Socks", url)));
+        let html = render(&Items {
+            own_list: false,
+            items,
+        })
+        .0;
+        assert_escaped(&html);
+        assert_no_script_urls(&html);
+        // The buyer's name is shown too
+        assert!(
+            html.contains("<td class='taken-by'>x&#x27; onmouseover"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn allocated_item_escapes_names_and_drops_script_urls() {
+        let html = render_all([SCRIPT, HANDLER].map(|name| AllocatedItem {
+            item: present(name, "https://example.com"),
+        }));
+        assert_escaped(&html);
+
+        let html = render_all(URLS.map(|url| AllocatedItem {
+            item: present("Socks", url),
+        }));
+        assert_no_script_urls(&html);
+    }
+
+    #[test]
+    fn web_urls_are_kept_but_escaped() {
+        let html = render(&ItemRow {
+            item: present("Socks", "https://example.com/?q='><script>"),
+        })
+        .0;
+        assert!(!html.contains("<script"), "{}", html);
+        assert!(
+            html.contains("href='https://example.com/?q=&#x27;&gt;&lt;script&gt;'"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn users_select_escapes_usernames() {
+        let html = render(&UsersSelect {
+            own_id: 1,
+            users: vec![(2, SCRIPT.to_string()), (3, HANDLER.to_string())],
+        })
+        .0;
+        assert_escaped(&html);
+    }
+
+    #[test]
+    fn admin_user_row_escapes_usernames_and_emails() {
+        let html =
+            render_all(
+                [(SCRIPT, true), (HANDLER, false)].map(|(name, active)| AdminUserRow {
+                    user: AdminUser {
+                        id: 1,
+                        username: name.to_string(),
+                        email: format!("{}@example.com", name),
+                        role: "user".to_string(),
+                        active,
+                        locale: "en".to_string(),
+                        sessions: 0,
+                    },
+                }),
+            );
+        assert_escaped(&html);
+        // Inside the confirmation for deactivating and revoking sessions
+        assert!(
+            html.contains("hx-confirm='Deactivate x&#x27;&gt;&lt;script&gt;"),
+            "{}",
+            html
+        );
+        assert!(
+            html.contains("hx-confirm='Log x&#x27; onmouseover=&#x27;alert(1) out"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn sessions_escape_the_user_agent_and_ip_address() {
+        let html = render(&Sessions {
+            sessions: [SCRIPT, HANDLER]
+                .into_iter()
+                .enumerate()
+                .map(|(id, payload)| Session {
+                    id: id as i32,
+                    // Both come straight from request headers
+                    user_agent: Some(payload.to_string()),
+                    ip_address: Some(payload.to_string()),
+                    created: Some(0),
+                    last_seen: None,
+                    current: false,
+                })
+                .collect(),
+        })
+        .0;
+        assert_escaped(&html);
+        assert!(
+            html.contains("<td>x&#x27;&gt;&lt;script&gt;alert(1)&lt;/script&gt;</td>"),
+            "{}",
+            html
+        );
+    }
+
+    #[test]
+    fn api_tokens_escape_names() {
+        let html = render(&ApiTokens {
+            tokens: [SCRIPT, HANDLER]
+                .into_iter()
+                .map(|name| ApiToken {
+                    id: 1,
+                    name: name.to_string(),
+                    scope: "read".to_string(),
+                    created: 0,
+                    last_used: None,
+                })
+                .collect(),
+        })
+        .0;
+        assert_escaped(&html);
+    }
+
+    #[test]
+    fn passkeys_escape_names() {
+        let html = render(&Passkeys {
+            passkeys: [SCRIPT, HANDLER]
+                .into_iter()
+                .map(|name| Passkey {
+                    id: 1,
+                    name: Some(name.to_string()),
+                    created: 0,
+                    last_used: Some(0),
+                })
+                .collect(),
+        })
+        .0;
+        assert_escaped(&html);
+    }
+
+    #[test]
+    fn oidc_error_escapes_the_message() {
+        // Escaped in case a message ever includes something from the callback URL
+        let html = render_all([SCRIPT, HANDLER].map(|message| OidcError { message }));
+        assert_escaped(&html);
+    }
+}
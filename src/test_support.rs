@@ -0,0 +1,403 @@
+use crate::{
+    auth_and_login, config, email, oidc, rate_limit, tables, utilities, webauthn, AppState,
+};
+use axum::{
+    extract::{Form, Query},
+    http::{HeaderMap, StatusCode},
+    routing::{get, post},
+    Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value as CborValue;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use p256::{
+    ecdsa::{signature::Signer, Signature as EcdsaSignature, SigningKey},
+    pkcs8::EncodePrivateKey,
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Each connection to an in-memory database gets its own, so only one is allowed
+pub async fn test_pool() -> SqlitePool {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    tables::create(pool.clone()).await;
+    pool
+}
+
+// Application state like main builds, with the test config, an in-memory database and captured emails
+pub async fn test_state(oidc_providers: Vec<config::OidcProvider>) -> AppState {
+    test_state_with_outbox(oidc_providers).await.0
+}
+
+// The same, along with the sender holding the emails the handlers send
+pub async fn test_state_with_outbox(
+    oidc_providers: Vec<config::OidcProvider>,
+) -> (AppState, Arc<email::MemorySender>) {
+    let mut app_config = config::test_config();
+    app_config.oidc_providers = oidc_providers;
+    let outbox = Arc::new(email::MemorySender::default());
+    let state = AppState {
+        connection_pool: test_pool().await,
+        email_sender: outbox.clone(),
+        login_limiter: Arc::new(rate_limit::LoginLimiter::new(
+            app_config.login_rate_limit.clone(),
+            Arc::new(rate_limit::SystemClock),
+        )),
+        http_client: reqwest::Client::new(),
+        app_config,
+    };
+    (state, outbox)
+}
+
+// Handlers send emails in the background, so wait a little for the next one to arrive
+pub async fn next_email(outbox: &email::MemorySender) -> email::SentEmail {
+    for _ in 0..100 {
+        {
+            let mut sent = outbox.sent.lock().unwrap();
+            if !sent.is_empty() {
+                return sent.remove(0);
+            }
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("No email was sent");
+}
+
+// The token from the link in an email
+pub fn emailed_token(sent: &email::SentEmail) -> String {
+    let (_, rest) = sent
+        .email
+        .text
+        .split_once("token=")
+        .expect("Email has no link with a token");
+    rest.split(char::is_whitespace).next().unwrap().to_string()
+}
+
+pub const MOCK_CLIENT_ID: &str = "christmas-lists";
+pub const MOCK_SUBJECT: &str = "ext-123";
+pub const MOCK_EMAIL: &str = "alice@example.com";
+const MOCK_KEY_ID: &str = "test-key";
+
+// An OIDC provider on a local port. It serves discovery and JWKS, and follows the authorization code
+// flow with PKCE, then hands out whatever ID token it was last given.
+pub struct MockProvider {
+    pub issuer: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    id_token: String,
+    // The authorization waiting to be redeemed, with the PKCE challenge it was started with
+    authorization: Option<MockAuthorization>,
+}
+
+struct MockAuthorization {
+    code: String,
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+}
+
+impl MockProvider {
+    pub async fn start() -> MockProvider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockState::default()));
+
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let point = MockProvider::key().verifying_key().to_encoded_point(false);
+        let jwks = json!({
+            "keys": [{
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": MOCK_KEY_ID,
+                "alg": "ES256",
+                "use": "sig",
+                "x": URL_SAFE_NO_PAD.encode(point.x().unwrap()),
+                "y": URL_SAFE_NO_PAD.encode(point.y().unwrap()),
+            }]
+        });
+        let authorizing = state.clone();
+        let issuing = state.clone();
+        let app = Router::new()
+            .route(
+                "/.well-known/openid-configuration",
+                get(move || {
+                    let discovery = discovery.clone();
+                    async move { Json(discovery) }
+                }),
+            )
+            .route(
+                "/jwks",
+                get(move || {
+                    let jwks = jwks.clone();
+                    async move { Json(jwks) }
+                }),
+            )
+            .route(
+                "/authorize",
+                get(move |Query(query): Query<HashMap<String, String>>| {
+                    let response = MockProvider::authorize(&authorizing, &query);
+                    async move { response }
+                }),
+            )
+            .route(
+                "/token",
+                post(move |Form(form): Form<HashMap<String, String>>| {
+                    let response = MockProvider::token(&issuing, &form);
+                    async move { response }
+                }),
+            );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        MockProvider { issuer, state }
+    }
+
+    // The user logs straight in, so the provider sends them back with a code
+    fn authorize(
+        state: &Mutex<MockState>,
+        query: &HashMap<String, String>,
+    ) -> (StatusCode, HeaderMap) {
+        let parameter = |name: &str| query.get(name).cloned().unwrap_or_default();
+        if parameter("code_challenge_method") != "S256" || parameter("code_challenge").is_empty() {
+            return (StatusCode::BAD_REQUEST, HeaderMap::new());
+        }
+        let code = auth_and_login::generate_token();
+        let redirect_uri = parameter("redirect_uri");
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Location",
+            format!(
+                "{}?code={}&state={}",
+                redirect_uri,
+                code,
+                utilities::percent_encode(&parameter("state"))
+            )
+            .parse()
+            .unwrap(),
+        );
+        state.lock().unwrap().authorization = Some(MockAuthorization {
+            code,
+            client_id: parameter("client_id"),
+            redirect_uri,
+            code_challenge: parameter("code_challenge"),
+        });
+        (StatusCode::SEE_OTHER, headers)
+    }
+
+    // Each code can be redeemed once, and only with the verifier for the challenge it was issued against
+    fn token(
+        state: &Mutex<MockState>,
+        form: &HashMap<String, String>,
+    ) -> (StatusCode, Json<Value>) {
+        let mut state = state.lock().unwrap();
+        let parameter = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+        let valid = state.authorization.take().is_some_and(|authorization| {
+            parameter("grant_type") == "authorization_code"
+                && parameter("code") == authorization.code
+                && parameter("client_id") == authorization.client_id
+                && parameter("redirect_uri") == authorization.redirect_uri
+                && form.get("code_verifier").is_some_and(|code_verifier| {
+                    oidc::code_challenge(code_verifier) == authorization.code_challenge
+                })
+        });
+        if !valid {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(json!({ "error": "invalid_grant" })),
+            );
+        }
+        (StatusCode::OK, Json(json!({ "id_token": state.id_token })))
+    }
+
+    // Follow an authorization URL as the user's browser would, returning the code the provider sends back
+    pub async fn log_in(&self, authorization_url: &str) -> Option<String> {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = client.get(authorization_url).send().await.unwrap();
+        let location =
+            reqwest::Url::parse(response.headers().get("Location")?.to_str().ok()?).ok()?;
+        location
+            .query_pairs()
+            .find(|(key, _)| key == "code")
+            .map(|(_, code)| code.into_owned())
+    }
+
+    // The key the provider publishes in its JWKS
+    pub fn key() -> SigningKey {
+        SigningKey::from_slice(&[7u8; 32]).unwrap()
+    }
+
+    pub fn provider(&self) -> config::OidcProvider {
+        config::OidcProvider {
+            name: "mock".to_string(),
+            display_name: "Mock".to_string(),
+            issuer: self.issuer.clone(),
+            client_id: MOCK_CLIENT_ID.to_string(),
+            client_secret: None,
+            scopes: vec!["openid".to_string(), "email".to_string()],
+        }
+    }
+
+    // Claims for a valid ID token, for tests to tamper with before signing
+    pub fn claims(&self, nonce: &str) -> Value {
+        let now = utilities::get_epoch_time();
+        json!({
+            "iss": self.issuer,
+            "aud": MOCK_CLIENT_ID,
+            "sub": MOCK_SUBJECT,
+            "email": MOCK_EMAIL,
+            "email_verified": true,
+            "nonce": nonce,
+            "iat": now,
+            "exp": now + 300,
+        })
+    }
+
+    pub fn sign(claims: &Value, key: &SigningKey) -> String {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(MOCK_KEY_ID.to_string());
+        let key = EncodingKey::from_ec_der(key.to_pkcs8_der().unwrap().as_bytes());
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    // Have the token endpoint return this ID token
+    pub fn issue(&self, id_token: String) {
+        self.state.lock().unwrap().id_token = id_token;
+    }
+}
+
+// A software authenticator with a fixed ES256 key, standing in for a security key or phone
+pub struct Authenticator {
+    pub key: SigningKey,
+    pub credential_id: Vec<u8>,
+}
+
+impl Authenticator {
+    pub fn new() -> Authenticator {
+        Authenticator {
+            key: SigningKey::from_slice(&[7u8; 32]).unwrap(),
+            credential_id: vec![1, 2, 3, 4, 5, 6, 7, 8],
+        }
+    }
+
+    pub fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let key = CborValue::Map(vec![
+            (CborValue::Integer(1.into()), CborValue::Integer(2.into())),
+            (
+                CborValue::Integer(3.into()),
+                CborValue::Integer(webauthn::ES256.into()),
+            ),
+            (
+                CborValue::Integer((-1).into()),
+                CborValue::Integer(1.into()),
+            ),
+            (
+                CborValue::Integer((-2).into()),
+                CborValue::Bytes(point.x().unwrap().to_vec()),
+            ),
+            (
+                CborValue::Integer((-3).into()),
+                CborValue::Bytes(point.y().unwrap().to_vec()),
+            ),
+        ]);
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&key, &mut bytes).unwrap();
+        bytes
+    }
+
+    pub fn authenticator_data(rp_id: &str, flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&sign_count.to_be_bytes());
+        data
+    }
+
+    pub fn attested_credential_data(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 16];
+        data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        data.extend_from_slice(&self.credential_id);
+        data.extend_from_slice(&self.cose_key());
+        data
+    }
+
+    pub fn attestation_object(authenticator_data: Vec<u8>) -> Vec<u8> {
+        let attestation = CborValue::Map(vec![
+            (
+                CborValue::Text("fmt".to_string()),
+                CborValue::Text("none".to_string()),
+            ),
+            (
+                CborValue::Text("attStmt".to_string()),
+                CborValue::Map(vec![]),
+            ),
+            (
+                CborValue::Text("authData".to_string()),
+                CborValue::Bytes(authenticator_data),
+            ),
+        ]);
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&attestation, &mut bytes).unwrap();
+        bytes
+    }
+
+    // The client data JSON and attestation object a browser would send for a registration
+    pub fn register(&self, rp_id: &str, origin: &str, challenge: &str) -> (Vec<u8>, Vec<u8>) {
+        let mut authenticator_data = Authenticator::authenticator_data(
+            rp_id,
+            webauthn::USER_PRESENT | webauthn::ATTESTED_CREDENTIAL_DATA,
+            0,
+        );
+        authenticator_data.extend_from_slice(&self.attested_credential_data());
+        (
+            client_data("webauthn.create", challenge, origin),
+            Authenticator::attestation_object(authenticator_data),
+        )
+    }
+
+    // The client data JSON, authenticator data and signature a browser would send for a login
+    pub fn login(
+        &self,
+        rp_id: &str,
+        origin: &str,
+        challenge: &str,
+        flags: u8,
+        sign_count: u32,
+    ) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let client_data = client_data("webauthn.get", challenge, origin);
+        let authenticator_data = Authenticator::authenticator_data(rp_id, flags, sign_count);
+        let mut signed_data = authenticator_data.clone();
+        signed_data.extend_from_slice(&Sha256::digest(&client_data));
+        let signature: EcdsaSignature = self.key.sign(&signed_data);
+        (
+            client_data,
+            authenticator_data,
+            signature.to_der().as_bytes().to_vec(),
+        )
+    }
+}
+
+pub fn client_data(ceremony: &str, challenge: &str, origin: &str) -> Vec<u8> {
+    serde_json::to_vec(&serde_json::json!({
+        "type": ceremony,
+        "challenge": challenge,
+        "origin": origin,
+    }))
+    .unwrap()
+}
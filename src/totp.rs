@@ -0,0 +1,133 @@
+use crate::utilities;
+use anyhow::Error;
+use hmac::{Hmac, Mac};
+use qrcode::{render::svg, QrCode};
+use rand::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use sha1::Sha1;
+
+// RFC 6238 defaults, which is what authenticator apps expect
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+// Accept codes from one step either side to allow for clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+
+// Generate a new random 160 bit secret, base32 encoded
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut secret);
+    base32::encode(base32::Alphabet::Rfc4648 { padding: false }, &secret)
+}
+
+// The otpauth URI that authenticator apps use to add the account
+pub fn provisioning_uri(secret: &str, username: &str, issuer: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        utilities::percent_encode(issuer),
+        utilities::percent_encode(username),
+        secret,
+        utilities::percent_encode(issuer),
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+// Render the provisioning URI as an SVG QR code
+pub fn qr_code_svg(uri: &str) -> Result<String, Error> {
+    Ok(QrCode::new(uri.as_bytes())?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+// Check a code against the secret, returning the matching time step if it is valid
+pub fn verify_code(secret: &str, code: &str, time: i64) -> Option<i64> {
+    let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let current_step = time.div_euclid(STEP_SECONDS);
+    (current_step - ALLOWED_DRIFT_STEPS..=current_step + ALLOWED_DRIFT_STEPS)
+        .find(|step| code_for_step(&key, *step) == code)
+}
+
+fn code_for_step(key: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation from RFC 4226
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        binary % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+// The code an authenticator app would show at the given time
+#[cfg(test)]
+pub fn code_at(secret: &str, time: i64) -> String {
+    let key = base32::decode(base32::Alphabet::Rfc4648 { padding: false }, secret).unwrap();
+    code_for_step(&key, time.div_euclid(STEP_SECONDS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The ASCII secret 12345678901234567890 from the RFC 6238 test vectors
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_the_rfc_6238_sha1_test_vectors() {
+        // The RFC gives 8 digit codes, authenticator apps show the last 6
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(code_at(SECRET, time), code);
+            assert_eq!(verify_code(SECRET, code, time), Some(time / STEP_SECONDS));
+        }
+    }
+
+    #[test]
+    fn accepts_codes_one_step_either_side() {
+        let step = 1111111109 / STEP_SECONDS;
+        assert_eq!(verify_code(SECRET, "081804", 1111111109 - 30), Some(step));
+        assert_eq!(verify_code(SECRET, "081804", 1111111109 + 30), Some(step));
+        assert_eq!(verify_code(SECRET, "081804", 1111111109 - 60), None);
+        assert_eq!(verify_code(SECRET, "081804", 1111111109 + 60), None);
+    }
+
+    #[test]
+    fn rejects_codes_of_the_wrong_length() {
+        assert_eq!(verify_code(SECRET, "28708", 59), None);
+        assert_eq!(verify_code(SECRET, "0287082", 59), None);
+        assert_eq!(verify_code(SECRET, "94287082", 59), None);
+        assert_eq!(verify_code(SECRET, "", 59), None);
+    }
+
+    #[test]
+    fn ignores_whitespace_in_codes() {
+        assert_eq!(verify_code(SECRET, "287 082", 59), Some(1));
+        assert_eq!(verify_code(SECRET, " 287082\n", 59), Some(1));
+    }
+
+    #[test]
+    fn rejects_a_wrong_code_or_bad_secret() {
+        assert_eq!(verify_code(SECRET, "287083", 59), None);
+        assert_eq!(verify_code("not base32!", "287082", 59), None);
+    }
+}
@@ -1,5 +1,6 @@
 use http::header::HeaderMap;
 use num::{Float, FromPrimitive};
+use std::net::SocketAddr;
 use std::time::SystemTime;
 // Convert a floating point number to pounds and pence
 pub fn format_currency<T>(currency: T) -> String
@@ -28,12 +29,66 @@ pub fn get_epoch_time() -> i64 {
         .unwrap()
 }
 
-pub fn get_user_id_from_header(headers: HeaderMap) -> i32 {
-    headers
-        .get("user-id")
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .parse()
-        .unwrap()
+// Format epoch seconds as a UTC date and time, e.g. 2023-12-25 09:30 UTC
+pub fn format_timestamp(epoch: i64) -> String {
+    let days = epoch.div_euclid(86400);
+    let seconds_of_day = epoch.rem_euclid(86400);
+
+    // Convert days since 1970-01-01 to a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        (seconds_of_day % 3600) / 60
+    )
+}
+
+// Work out the client's IP, using the address added by our reverse proxy if there is one
+pub fn get_client_ip(headers: &HeaderMap, addr: SocketAddr, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for {
+        let forwarded = headers
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty());
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    addr.ip().to_string()
+}
+
+// Percent-encode everything except unreserved characters, for building URLs
+pub fn percent_encode(value: &str) -> String {
+    let mut encoded = String::new();
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Only http and https links are shown, so a javascript: or data: URL can't run anything when clicked
+pub fn is_web_url(url: &str) -> bool {
+    reqwest::Url::parse(url.trim())
+        .map(|url| url.scheme() == "http" || url.scheme() == "https")
+        .unwrap_or(false)
 }
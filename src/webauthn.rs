@@ -0,0 +1,451 @@
+use anyhow::{anyhow, Error};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use p256::ecdsa::{signature::Verifier, Signature as EcdsaSignature, VerifyingKey as EcdsaKey};
+use rand::RngCore;
+use rand_chacha::rand_core::SeedableRng;
+use rsa::{pkcs1v15, BigUint, RsaPublicKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+
+// COSE algorithm identifiers for the signatures we accept
+pub const ES256: i64 = -7;
+pub const RS256: i64 = -257;
+
+// Authenticator data flags
+pub const USER_PRESENT: u8 = 0x01;
+pub const ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+// A credential from a successful registration, ready to be stored
+pub struct NewCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+// A random challenge for the authenticator to sign, base64url encoded
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    rand_chacha::ChaCha20Rng::from_entropy().fill_bytes(&mut challenge);
+    encode(&challenge)
+}
+
+pub fn encode(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    Ok(URL_SAFE_NO_PAD.decode(value.trim_end_matches('='))?)
+}
+
+// Check the client data is for the expected ceremony and origin, returning the challenge it signed
+fn check_client_data(
+    client_data_json: &[u8],
+    expected_ceremony: &str,
+    expected_origin: &str,
+) -> Result<String, Error> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)?;
+    if client_data.ceremony != expected_ceremony {
+        return Err(anyhow!("Unexpected ceremony {}", client_data.ceremony));
+    }
+    if client_data.origin != expected_origin {
+        return Err(anyhow!("Unexpected origin {}", client_data.origin));
+    }
+    Ok(client_data.challenge)
+}
+
+// Check the authenticator data is for our relying party with the user present, returning its flags and counter
+fn check_authenticator_data(authenticator_data: &[u8], rp_id: &str) -> Result<(u8, u32), Error> {
+    if authenticator_data.len() < 37 {
+        return Err(anyhow!("Authenticator data is too short"));
+    }
+    if authenticator_data[0..32] != Sha256::digest(rp_id.as_bytes())[..] {
+        return Err(anyhow!(
+            "Authenticator data is for a different relying party"
+        ));
+    }
+    let flags = authenticator_data[32];
+    if flags & USER_PRESENT == 0 {
+        return Err(anyhow!("User was not present"));
+    }
+    let sign_count = u32::from_be_bytes(authenticator_data[33..37].try_into()?);
+    Ok((flags, sign_count))
+}
+
+// Verify a registration response, returning the challenge it answered and the new credential
+pub fn verify_registration(
+    client_data_json: &[u8],
+    attestation_object: &[u8],
+    rp_id: &str,
+    origin: &str,
+) -> Result<(String, NewCredential), Error> {
+    let challenge = check_client_data(client_data_json, "webauthn.create", origin)?;
+
+    // We ask for no attestation, so only the authenticator data is needed from the attestation object
+    let attestation: Value = ciborium::de::from_reader(attestation_object)?;
+    let authenticator_data = map_get(&attestation, &Value::Text("authData".to_string()))
+        .and_then(|value| value.as_bytes())
+        .ok_or_else(|| anyhow!("Attestation object has no authenticator data"))?;
+
+    let (flags, sign_count) = check_authenticator_data(authenticator_data, rp_id)?;
+    if flags & ATTESTED_CREDENTIAL_DATA == 0 {
+        return Err(anyhow!("Authenticator data has no credential"));
+    }
+
+    // Attested credential data is the AAGUID, then a length prefixed credential ID, then the COSE key
+    let credential_data = &authenticator_data[37..];
+    if credential_data.len() < 18 {
+        return Err(anyhow!("Attested credential data is too short"));
+    }
+    let id_length = u16::from_be_bytes([credential_data[16], credential_data[17]]) as usize;
+    if credential_data.len() < 18 + id_length {
+        return Err(anyhow!("Credential ID is truncated"));
+    }
+    let credential_id = &credential_data[18..18 + id_length];
+
+    let mut cursor = Cursor::new(&credential_data[18 + id_length..]);
+    let cose_key: Value = ciborium::de::from_reader(&mut cursor)?;
+    let public_key =
+        credential_data[18 + id_length..18 + id_length + cursor.position() as usize].to_vec();
+
+    // Make sure we'll be able to verify signatures with it later
+    parse_public_key(&cose_key)?;
+
+    Ok((
+        challenge,
+        NewCredential {
+            credential_id: encode(credential_id),
+            public_key,
+            sign_count,
+        },
+    ))
+}
+
+// Verify an authentication response against a stored credential, returning the challenge it answered and the new counter
+pub fn verify_authentication(
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    public_key: &[u8],
+    stored_sign_count: u32,
+    rp_id: &str,
+    origin: &str,
+) -> Result<(String, u32), Error> {
+    let challenge = check_client_data(client_data_json, "webauthn.get", origin)?;
+    let (_flags, sign_count) = check_authenticator_data(authenticator_data, rp_id)?;
+
+    let mut signed_data = authenticator_data.to_vec();
+    signed_data.extend_from_slice(&Sha256::digest(client_data_json));
+
+    let cose_key: Value = ciborium::de::from_reader(public_key)?;
+    match parse_public_key(&cose_key)? {
+        PublicKey::Es256(key) => key.verify(&signed_data, &EcdsaSignature::from_der(signature)?)?,
+        PublicKey::Rs256(key) => pkcs1v15::VerifyingKey::<Sha256>::new(key)
+            .verify(&signed_data, &pkcs1v15::Signature::try_from(signature)?)?,
+    }
+
+    // A counter that doesn't go up suggests the credential has been cloned
+    if (sign_count != 0 || stored_sign_count != 0) && sign_count <= stored_sign_count {
+        return Err(anyhow!("Signature counter did not increase"));
+    }
+
+    Ok((challenge, sign_count))
+}
+
+enum PublicKey {
+    Es256(EcdsaKey),
+    Rs256(RsaPublicKey),
+}
+
+fn parse_public_key(cose_key: &Value) -> Result<PublicKey, Error> {
+    let get_int = |label: i64| {
+        map_get(cose_key, &Value::Integer(label.into()))
+            .and_then(|value| value.as_integer())
+            .and_then(|value| i64::try_from(value).ok())
+    };
+    let get_bytes = |label: i64| {
+        map_get(cose_key, &Value::Integer(label.into()))
+            .and_then(|value| value.as_bytes())
+            .ok_or_else(|| anyhow!("Public key is missing parameter {}", label))
+    };
+
+    match get_int(3) {
+        Some(ES256) => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(get_bytes(-2)?);
+            point.extend_from_slice(get_bytes(-3)?);
+            Ok(PublicKey::Es256(EcdsaKey::from_sec1_bytes(&point)?))
+        }
+        Some(RS256) => Ok(PublicKey::Rs256(RsaPublicKey::new(
+            BigUint::from_bytes_be(get_bytes(-1)?),
+            BigUint::from_bytes_be(get_bytes(-2)?),
+        )?)),
+        _ => Err(anyhow!("Unsupported public key algorithm")),
+    }
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?
+        .iter()
+        .find(|(entry_key, _value)| entry_key == key)
+        .map(|(_key, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{client_data, Authenticator};
+    use p256::ecdsa::SigningKey;
+
+    const RP_ID: &str = "localhost";
+    const ORIGIN: &str = "http://localhost:3000";
+
+    fn registered_key(authenticator: &Authenticator) -> Vec<u8> {
+        let (client_data, attestation_object) = authenticator.register(RP_ID, ORIGIN, "challenge");
+        verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN)
+            .unwrap()
+            .1
+            .public_key
+    }
+
+    #[test]
+    fn registers_and_logs_in() {
+        let authenticator = Authenticator::new();
+        let (client_data, attestation_object) = authenticator.register(RP_ID, ORIGIN, "reg");
+        let (challenge, credential) =
+            verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).unwrap();
+        assert_eq!(challenge, "reg");
+        assert_eq!(
+            credential.credential_id,
+            encode(&authenticator.credential_id)
+        );
+        assert_eq!(credential.public_key, authenticator.cose_key());
+        assert_eq!(credential.sign_count, 0);
+
+        let (client_data, authenticator_data, signature) =
+            authenticator.login(RP_ID, ORIGIN, "log", USER_PRESENT, 1);
+        let (challenge, sign_count) = verify_authentication(
+            &client_data,
+            &authenticator_data,
+            &signature,
+            &credential.public_key,
+            0,
+            RP_ID,
+            ORIGIN,
+        )
+        .unwrap();
+        assert_eq!(challenge, "log");
+        assert_eq!(sign_count, 1);
+    }
+
+    #[test]
+    fn rejects_the_wrong_origin() {
+        let authenticator = Authenticator::new();
+        let (client_data, attestation_object) =
+            authenticator.register(RP_ID, "http://evil.example", "reg");
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+
+        let public_key = registered_key(&authenticator);
+        let (client_data, authenticator_data, signature) =
+            authenticator.login(RP_ID, "http://evil.example", "log", USER_PRESENT, 1);
+        assert!(verify_authentication(
+            &client_data,
+            &authenticator_data,
+            &signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_the_wrong_ceremony() {
+        let authenticator = Authenticator::new();
+        let (_client_data, attestation_object) = authenticator.register(RP_ID, ORIGIN, "reg");
+        let client_data = client_data("webauthn.get", "reg", ORIGIN);
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+    }
+
+    #[test]
+    fn rejects_a_different_relying_party() {
+        let authenticator = Authenticator::new();
+        let (client_data, attestation_object) =
+            authenticator.register("evil.example", ORIGIN, "reg");
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+
+        let public_key = registered_key(&authenticator);
+        let (client_data, authenticator_data, signature) =
+            authenticator.login("evil.example", ORIGIN, "log", USER_PRESENT, 1);
+        assert!(verify_authentication(
+            &client_data,
+            &authenticator_data,
+            &signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn requires_the_user_to_be_present() {
+        let authenticator = Authenticator::new();
+        let mut authenticator_data =
+            Authenticator::authenticator_data(RP_ID, ATTESTED_CREDENTIAL_DATA, 0);
+        authenticator_data.extend_from_slice(&authenticator.attested_credential_data());
+        let attestation_object = Authenticator::attestation_object(authenticator_data);
+        let client_data = client_data("webauthn.create", "reg", ORIGIN);
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+
+        let public_key = registered_key(&authenticator);
+        let (client_data, authenticator_data, signature) =
+            authenticator.login(RP_ID, ORIGIN, "log", 0, 1);
+        assert!(verify_authentication(
+            &client_data,
+            &authenticator_data,
+            &signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_authenticator_data() {
+        let authenticator = Authenticator::new();
+        let client_data = client_data("webauthn.create", "reg", ORIGIN);
+
+        let authenticator_data =
+            Authenticator::authenticator_data(RP_ID, USER_PRESENT | ATTESTED_CREDENTIAL_DATA, 0);
+        let attestation_object =
+            Authenticator::attestation_object(authenticator_data[..36].to_vec());
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+
+        // No attested credential data at all, despite the flag saying there is
+        let attestation_object = Authenticator::attestation_object(authenticator_data.clone());
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+
+        // The credential ID claims to be longer than what follows it
+        let mut truncated = authenticator_data;
+        truncated.extend_from_slice(&[0u8; 16]);
+        truncated.extend_from_slice(&64u16.to_be_bytes());
+        truncated.extend_from_slice(&authenticator.credential_id);
+        let attestation_object = Authenticator::attestation_object(truncated);
+        assert!(verify_registration(&client_data, &attestation_object, RP_ID, ORIGIN).is_err());
+
+        let public_key = registered_key(&authenticator);
+        let (client_data, authenticator_data, signature) =
+            authenticator.login(RP_ID, ORIGIN, "log", USER_PRESENT, 1);
+        assert!(verify_authentication(
+            &client_data,
+            &authenticator_data[..36],
+            &signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn requires_the_counter_to_increase() {
+        let authenticator = Authenticator::new();
+        let public_key = registered_key(&authenticator);
+
+        let (client_data, authenticator_data, signature) =
+            authenticator.login(RP_ID, ORIGIN, "log", USER_PRESENT, 5);
+        let verify = |stored_sign_count| {
+            verify_authentication(
+                &client_data,
+                &authenticator_data,
+                &signature,
+                &public_key,
+                stored_sign_count,
+                RP_ID,
+                ORIGIN,
+            )
+        };
+        assert!(verify(4).is_ok());
+        assert!(verify(5).is_err());
+        assert!(verify(6).is_err());
+
+        // Authenticators that don't keep a counter always send zero
+        let (client_data, authenticator_data, signature) =
+            authenticator.login(RP_ID, ORIGIN, "log", USER_PRESENT, 0);
+        assert!(verify_authentication(
+            &client_data,
+            &authenticator_data,
+            &signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let authenticator = Authenticator::new();
+        let public_key = registered_key(&authenticator);
+        let (client_data_json, authenticator_data, signature) =
+            authenticator.login(RP_ID, ORIGIN, "log", USER_PRESENT, 1);
+
+        // Signed by a different key
+        let other = Authenticator {
+            key: SigningKey::from_slice(&[9u8; 32]).unwrap(),
+            credential_id: vec![],
+        };
+        let (_client_data, _authenticator_data, other_signature) =
+            other.login(RP_ID, ORIGIN, "log", USER_PRESENT, 1);
+        assert!(verify_authentication(
+            &client_data_json,
+            &authenticator_data,
+            &other_signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+
+        // The right signature over different client data
+        let tampered = client_data("webauthn.get", "other", ORIGIN);
+        assert!(verify_authentication(
+            &tampered,
+            &authenticator_data,
+            &signature,
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+
+        assert!(verify_authentication(
+            &client_data_json,
+            &authenticator_data,
+            &signature[..signature.len() - 1],
+            &public_key,
+            0,
+            RP_ID,
+            ORIGIN
+        )
+        .is_err());
+    }
+}